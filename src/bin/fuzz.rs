@@ -11,7 +11,6 @@ fn rand_exp_size(rng: &mut dyn RngCore) -> u64 {
 fn generate_old_entries(rng: &mut dyn RngCore) -> Vec<Entry> {
     let size = rand_exp_size(rng);
     (0..size)
-        .into_iter()
         .map(|i| Entry::new(Some(i), format!("{i}.txt")))
         .collect()
 }
@@ -24,7 +23,6 @@ fn generate_new_entries(old_entries_len: u64, rng: &mut dyn RngCore) -> Vec<Entr
         rng.next_u64() % 101
     };
     (0..size)
-        .into_iter()
         .map(|i| {
             let v = rng.next_u64() % 100;
             let id = if v < new_file_percentage {
@@ -37,8 +35,8 @@ fn generate_new_entries(old_entries_len: u64, rng: &mut dyn RngCore) -> Vec<Entr
         .collect()
 }
 
-fn sort(entries: &mut Vec<Entry>) {
-    entries.sort_by(|a, b| a.path.as_str().cmp(b.path.as_str()));
+fn sort(entries: &mut [Entry]) {
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
 }
 
 fn run(seed: u64) {
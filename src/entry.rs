@@ -1,11 +1,109 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink { target: PathBuf },
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Owner {
+    pub user: String,
+    pub group: String,
+}
+
+/// Metadata that is only shown next to an entry, never edited
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Info {
+    pub size: u64,
+    /// Seconds since the epoch
+    pub mtime: i64,
+    pub file_type: &'static str,
+    /// The `git status --short` marker, for changed, untracked and ignored files
+    pub git_status: Option<String>,
+}
+
+/// What a listed file looked like, to tell whether it changed since
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub ino: u64,
+    pub size: u64,
+    /// Seconds and nanoseconds since the epoch
+    pub mtime: (i64, i64),
+    /// Of the content, only taken for regular files when asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<u64>,
+}
+
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct Entry {
     pub id: Option<u64>,
-    pub path: String,
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    /// Permission bits, only known when the mode column is shown
+    pub mode: Option<u32>,
+    /// Owning user and group, only known when the owner column is shown
+    pub owner: Option<Owner>,
+    /// Only known for the listed entries when annotation columns are shown
+    pub info: Option<Info>,
+    /// Only known for the listed entries
+    pub fingerprint: Option<Fingerprint>,
+    /// Only set on edited entries, the path should be a hard link
+    /// to the file with the same id rather than a copy of it
+    pub hard_link: bool,
 }
 
 impl Entry {
-    pub fn new(id: Option<u64>, path: String) -> Self {
-        Entry { id, path }
+    pub fn new(id: Option<u64>, path: impl Into<PathBuf>) -> Self {
+        Entry {
+            id,
+            path: path.into(),
+            kind: EntryKind::File,
+            mode: None,
+            owner: None,
+            info: None,
+            fingerprint: None,
+            hard_link: false,
+        }
+    }
+
+    // directories are identified by their path, they never carry an id
+    pub fn new_dir(path: impl Into<PathBuf>) -> Self {
+        Entry {
+            id: None,
+            path: path.into(),
+            kind: EntryKind::Dir,
+            mode: None,
+            owner: None,
+            info: None,
+            fingerprint: None,
+            hard_link: false,
+        }
+    }
+
+    pub fn new_symlink(
+        id: Option<u64>,
+        path: impl Into<PathBuf>,
+        target: impl Into<PathBuf>,
+    ) -> Self {
+        Entry {
+            id,
+            path: path.into(),
+            kind: EntryKind::Symlink {
+                target: target.into(),
+            },
+            mode: None,
+            owner: None,
+            info: None,
+            fingerprint: None,
+            hard_link: false,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == EntryKind::Dir
     }
 }
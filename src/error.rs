@@ -1,13 +1,24 @@
-use std::{error::Error, fmt::Display, io, process};
+use std::{error::Error, fmt::Display, io, path::PathBuf, process};
 
 #[derive(Debug)]
 pub enum TreeEditError {
-    DuplicatePath(String),
+    DuplicatePath(PathBuf),
     InvalidEntry(String),
     InvalidFileId(u64),
+    UnknownUser(String),
+    UnknownGroup(String),
+    PathOutsideRoot(PathBuf),
     NoEditorAvailable(),
     EditorExitFailure(process::ExitStatus),
     FsChanged(DetectedBy),
+    /// Applying failed, with whatever went wrong while rolling back
+    ApplyFailed(Box<TreeEditError>, Vec<TreeEditError>),
+    RollbackFailed(Vec<TreeEditError>),
+    UnfinishedSession(PathBuf),
+    /// Rolling back was cut short, the changes can no longer be finished
+    RollbackCutShort(PathBuf),
+    InvalidPlan(serde_json::Error),
+    NotInGitWorkTree(PathBuf),
     IOError(io::Error),
 }
 
@@ -17,9 +28,18 @@ impl Error for TreeEditError {
             TreeEditError::DuplicatePath(_) => None,
             TreeEditError::InvalidEntry(_) => None,
             TreeEditError::InvalidFileId(_) => None,
+            TreeEditError::UnknownUser(_) => None,
+            TreeEditError::UnknownGroup(_) => None,
+            TreeEditError::PathOutsideRoot(_) => None,
             TreeEditError::NoEditorAvailable() => None,
             TreeEditError::EditorExitFailure(_) => None,
             TreeEditError::FsChanged(_) => None,
+            TreeEditError::ApplyFailed(ref source, _) => Some(source.as_ref()),
+            TreeEditError::RollbackFailed(_) => None,
+            TreeEditError::UnfinishedSession(_) => None,
+            TreeEditError::RollbackCutShort(_) => None,
+            TreeEditError::InvalidPlan(ref source) => Some(source),
+            TreeEditError::NotInGitWorkTree(_) => None,
             TreeEditError::IOError(ref source) => Some(source),
         }
     }
@@ -28,9 +48,16 @@ impl Error for TreeEditError {
 impl Display for TreeEditError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            TreeEditError::DuplicatePath(path) => write!(f, "duplicate path {}", path),
+            TreeEditError::DuplicatePath(path) => write!(f, "duplicate path {}", path.display()),
             TreeEditError::InvalidEntry(entry) => write!(f, "invalid entry {}", entry),
             TreeEditError::InvalidFileId(id) => write!(f, "invalid file id {}", id),
+            TreeEditError::UnknownUser(user) => write!(f, "unknown user {}", user),
+            TreeEditError::UnknownGroup(group) => write!(f, "unknown group {}", group),
+            TreeEditError::PathOutsideRoot(path) => write!(
+                f,
+                "path {} is outside of the root directory, use --allow-outside-root to allow it",
+                path.display()
+            ),
             TreeEditError::NoEditorAvailable() => write!(f, "no editor available, try setting $VISUAL or $EDITOR environment variable in your shell"),
             TreeEditError::EditorExitFailure(status) => {
                 write!(f, "editor {}", status)
@@ -38,6 +65,37 @@ impl Display for TreeEditError {
             TreeEditError::FsChanged(detected_by) => {
                 write!(f, "file system changed while editing: {}", detected_by)
             }
+            TreeEditError::ApplyFailed(error, rollback_errors) => {
+                if rollback_errors.is_empty() {
+                    return write!(f, "{}, all changes were rolled back", error);
+                }
+                write!(f, "{}, rolling back failed too:", error)?;
+                for rollback_error in rollback_errors {
+                    write!(f, "\n  {}", rollback_error)?;
+                }
+                write!(f, "\nrun `tree-edit undo` to retry reverting the remaining changes")
+            }
+            TreeEditError::RollbackFailed(errors) => {
+                write!(f, "rolling back failed:")?;
+                for error in errors {
+                    write!(f, "\n  {}", error)?;
+                }
+                write!(f, "\nrun `tree-edit undo` to retry reverting the remaining changes")
+            }
+            TreeEditError::UnfinishedSession(root) => write!(
+                f,
+                "changes to {} were cut short, run `tree-edit resume` to finish or roll them back",
+                root.display()
+            ),
+            TreeEditError::RollbackCutShort(root) => write!(
+                f,
+                "rolling back changes to {} was cut short, run `tree-edit resume --rollback` to finish it",
+                root.display()
+            ),
+            TreeEditError::InvalidPlan(source) => write!(f, "invalid plan: {}", source),
+            TreeEditError::NotInGitWorkTree(path) => {
+                write!(f, "{} is not inside a git work tree", path.display())
+            }
             TreeEditError::IOError(ref source) => source.fmt(f),
         }
     }
@@ -51,15 +109,24 @@ impl From<io::Error> for TreeEditError {
 
 #[derive(Debug)]
 pub enum DetectedBy {
-    FileNotFound(String),
-    FileExists(String),
+    FileNotFound(PathBuf),
+    FileExists(PathBuf),
+    /// Another file took its place
+    InodeChanged(PathBuf),
+    /// Its size, modification time or content is not what was listed
+    ContentChanged(PathBuf),
+    /// A file is where a directory is needed
+    NotADirectory(PathBuf),
 }
 
 impl Display for DetectedBy {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            DetectedBy::FileNotFound(path) => write!(f, "file not found {}", path),
-            DetectedBy::FileExists(path) => write!(f, "file already exists {}", path),
+            DetectedBy::FileNotFound(path) => write!(f, "file not found {}", path.display()),
+            DetectedBy::FileExists(path) => write!(f, "file already exists {}", path.display()),
+            DetectedBy::InodeChanged(path) => write!(f, "file replaced {}", path.display()),
+            DetectedBy::ContentChanged(path) => write!(f, "file modified {}", path.display()),
+            DetectedBy::NotADirectory(path) => write!(f, "not a directory {}", path.display()),
         }
     }
 }
@@ -0,0 +1,113 @@
+use std::{
+    fs::{self, File},
+    hash::{DefaultHasher, Hasher},
+    io::{self, Read},
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+use crate::entry::Fingerprint;
+use crate::error::{DetectedBy, TreeEditError};
+
+/// Takes the fingerprint of a file as it is now, hashing its content if asked to
+pub fn take(path: &Path, metadata: &fs::Metadata, hash: bool) -> io::Result<Fingerprint> {
+    let hash = match hash && metadata.is_file() {
+        true => Some(hash_content(path)?),
+        false => None,
+    };
+    Ok(Fingerprint {
+        ino: metadata.ino(),
+        size: metadata.len(),
+        mtime: (metadata.mtime(), metadata.mtime_nsec()),
+        hash,
+    })
+}
+
+/// Checks that a file is still the one the fingerprint was taken of,
+/// with the same content
+pub fn check(path: &Path, fingerprint: &Fingerprint) -> crate::Result<()> {
+    let changed = |detected_by: fn(_) -> DetectedBy| {
+        Err(TreeEditError::FsChanged(detected_by(path.to_path_buf())))
+    };
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return changed(DetectedBy::FileNotFound),
+        Err(e) => return Err(e.into()),
+    };
+    if metadata.ino() != fingerprint.ino {
+        return changed(DetectedBy::InodeChanged);
+    }
+    // what is in a directory is listed on its own
+    if metadata.is_dir() {
+        return Ok(());
+    }
+    let current = take(path, &metadata, fingerprint.hash.is_some())?;
+    if current.size != fingerprint.size
+        || current.mtime != fingerprint.mtime
+        || current.hash != fingerprint.hash
+    {
+        return changed(DetectedBy::ContentChanged);
+    }
+    Ok(())
+}
+
+fn hash_content(path: &Path) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let mut hasher = DefaultHasher::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(hasher.finish()),
+            n => hasher.write(&buffer[..n]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsutils::tmpfile::TmpDir;
+
+    #[test]
+    fn test_check() -> crate::Result<()> {
+        let tmp = TmpDir::new("tree-edit-fingerprint")?;
+        let dir = tmp.path();
+        let path = dir.join("file");
+        fs::write(&path, "content")?;
+        let metadata = fs::symlink_metadata(&path)?;
+        let fingerprint = take(&path, &metadata, false)?;
+        let hashed = take(&path, &metadata, true)?;
+        assert!(fingerprint.hash.is_none() && hashed.hash.is_some());
+        check(&path, &fingerprint)?;
+        check(&path, &hashed)?;
+        // same size, and the modification time put back as it was
+        fs::write(&path, "CONTENT")?;
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(metadata.modified()?)?;
+        check(&path, &fingerprint)?;
+        assert!(matches!(
+            check(&path, &hashed),
+            Err(TreeEditError::FsChanged(DetectedBy::ContentChanged(_)))
+        ));
+        fs::write(&path, "longer content")?;
+        assert!(matches!(
+            check(&path, &fingerprint),
+            Err(TreeEditError::FsChanged(DetectedBy::ContentChanged(_)))
+        ));
+        let other = dir.join("other");
+        fs::write(&other, "content")?;
+        fs::rename(&other, &path)?;
+        assert!(matches!(
+            check(&path, &fingerprint),
+            Err(TreeEditError::FsChanged(DetectedBy::InodeChanged(_)))
+        ));
+        fs::remove_file(&path)?;
+        assert!(matches!(
+            check(&path, &fingerprint),
+            Err(TreeEditError::FsChanged(DetectedBy::FileNotFound(_)))
+        ));
+        Ok(())
+    }
+}
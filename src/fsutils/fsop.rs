@@ -1,101 +1,602 @@
-use std::{borrow::Cow, fs, path::Path};
+use std::{
+    fs, io,
+    os::unix::fs::{lchown, symlink, PermissionsExt},
+    path::{Path, PathBuf},
+};
 
+use serde::{Deserialize, Serialize};
+
+use crate::entry::Fingerprint;
 use crate::error::{DetectedBy, TreeEditError};
+use crate::fsutils::{owner, trash};
 
-#[derive(Debug)]
-pub enum FsOp<'a> {
+// serialized as `{"op": "move_file", "src": "a", "dst": "b"}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum FsOp {
     CreateFile {
-        path: Cow<'a, str>,
+        #[serde(with = "lossless")]
+        path: PathBuf,
+    },
+    CreateDir {
+        #[serde(with = "lossless")]
+        path: PathBuf,
+    },
+    CreateSymlink {
+        #[serde(with = "lossless")]
+        path: PathBuf,
+        #[serde(with = "lossless")]
+        target: PathBuf,
+    },
+    RetargetSymlink {
+        #[serde(with = "lossless")]
+        path: PathBuf,
+        #[serde(with = "lossless")]
+        target: PathBuf,
     },
     MoveFile {
-        src: Cow<'a, str>,
-        dst: Cow<'a, str>,
+        #[serde(with = "lossless")]
+        src: PathBuf,
+        #[serde(with = "lossless")]
+        dst: PathBuf,
+    },
+    MoveDir {
+        #[serde(with = "lossless")]
+        src: PathBuf,
+        #[serde(with = "lossless")]
+        dst: PathBuf,
     },
     CopyFile {
-        src: Cow<'a, str>,
-        dst: Cow<'a, str>,
+        #[serde(with = "lossless")]
+        src: PathBuf,
+        #[serde(with = "lossless")]
+        dst: PathBuf,
+    },
+    HardLink {
+        #[serde(with = "lossless")]
+        src: PathBuf,
+        #[serde(with = "lossless")]
+        dst: PathBuf,
+    },
+    SetPermissions {
+        #[serde(with = "lossless")]
+        path: PathBuf,
+        #[serde(with = "octal")]
+        mode: u32,
+    },
+    Chown {
+        #[serde(with = "lossless")]
+        path: PathBuf,
+        user: String,
+        group: String,
     },
     RemoveFile {
-        path: Cow<'a, str>,
+        #[serde(with = "lossless")]
+        path: PathBuf,
+    },
+    TrashFile {
+        #[serde(with = "lossless")]
+        path: PathBuf,
+    },
+    RemoveDir {
+        #[serde(with = "lossless")]
+        path: PathBuf,
+    },
+    PruneDir {
+        #[serde(with = "lossless")]
+        path: PathBuf,
     },
 }
 
+/// The operations to apply, in order, as exported with `--output json`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Plan {
+    pub ops: Vec<FsOp>,
+    /// The listed files the operations take from, checked again before applying
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub listed: Vec<ListedFile>,
+}
+
+// serialized as `{"path": "a", "ino": 1, "size": 2, "mtime": [3, 4]}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListedFile {
+    #[serde(with = "lossless")]
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub fingerprint: Fingerprint,
+}
+
+impl FsOp {
+    /// The paths the operation changes, symlink targets are only ever text
+    pub fn paths(&self) -> Vec<&Path> {
+        match self {
+            FsOp::CreateFile { path }
+            | FsOp::CreateDir { path }
+            | FsOp::CreateSymlink { path, .. }
+            | FsOp::RetargetSymlink { path, .. }
+            | FsOp::SetPermissions { path, .. }
+            | FsOp::Chown { path, .. }
+            | FsOp::RemoveFile { path }
+            | FsOp::TrashFile { path }
+            | FsOp::RemoveDir { path }
+            | FsOp::PruneDir { path } => vec![path],
+            FsOp::MoveFile { src, dst }
+            | FsOp::MoveDir { src, dst }
+            | FsOp::CopyFile { src, dst }
+            | FsOp::HardLink { src, dst } => vec![src, dst],
+        }
+    }
+}
+
+// paths are strings, or arrays of bytes when they are not valid UTF-8
+mod lossless {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::{
+        ffi::OsString,
+        os::unix::ffi::{OsStrExt, OsStringExt},
+        path::{Path, PathBuf},
+    };
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(path) => serializer.serialize_str(path),
+            None => serializer.collect_seq(path.as_os_str().as_bytes()),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Str(String),
+            Bytes(Vec<u8>),
+        }
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Str(path) => PathBuf::from(path),
+            Repr::Bytes(path) => PathBuf::from(OsString::from_vec(path)),
+        })
+    }
+}
+
+// modes are octal strings, as they are written for chmod
+mod octal {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(mode: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:04o}", mode))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+        let mode = String::deserialize(deserializer)?;
+        u32::from_str_radix(&mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .ok_or_else(|| D::Error::custom(format!("invalid mode {}", mode)))
+    }
+}
+
 pub fn exec(op: &FsOp) -> crate::Result<()> {
+    check(op)?;
     match op {
-        FsOp::CreateFile { path: path_str } => {
-            let path = Path::new(path_str.as_ref());
-            if path.exists() {
-                return Err(TreeEditError::FsChanged(DetectedBy::FileExists(
-                    path_str.to_string(),
-                )));
-            }
+        FsOp::CreateFile { path } => {
             if let Some(parent) = path.parent() {
                 fs::create_dir_all(parent)?;
             }
             fs::OpenOptions::new()
-                .create(true)
+                .create_new(true)
                 .write(true)
-                .open(&path)?;
-        }
-        FsOp::MoveFile {
-            src: src_str,
-            dst: dst_str,
-        } => {
-            let src = Path::new(src_str.as_ref());
-            let dst = Path::new(dst_str.as_ref());
-            if !src.exists() {
-                return Err(TreeEditError::FsChanged(DetectedBy::FileNotFound(
-                    src_str.to_string(),
-                )));
-            }
-            if dst.exists() {
-                return Err(TreeEditError::FsChanged(DetectedBy::FileExists(
-                    dst_str.to_string(),
-                )));
+                .open(path)?;
+        }
+        // the directory may already have been created along the way
+        // by an earlier operation moving something into it
+        FsOp::CreateDir { path } => fs::create_dir_all(path)?,
+        FsOp::CreateSymlink { path, target } => {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
             }
+            symlink(target, path)?;
+        }
+        FsOp::RetargetSymlink { path, target } => {
+            fs::remove_file(path)?;
+            symlink(target, path)?;
+        }
+        FsOp::MoveFile { src, dst } | FsOp::MoveDir { src, dst } => {
             if let Some(dst_parent) = dst.parent() {
                 fs::create_dir_all(dst_parent)?;
             }
             fs::rename(src, dst)?;
         }
-        FsOp::CopyFile {
-            src: src_str,
-            dst: dst_str,
-        } => {
-            let src = Path::new(src_str.as_ref());
-            let dst = Path::new(dst_str.as_ref());
-            if !src.exists() {
-                return Err(TreeEditError::FsChanged(DetectedBy::FileNotFound(
-                    src_str.to_string(),
-                )));
-            }
-            if dst.exists() {
-                return Err(TreeEditError::FsChanged(DetectedBy::FileExists(
-                    dst_str.to_string(),
-                )));
+        FsOp::CopyFile { src, dst } => {
+            if let Some(dst_parent) = dst.parent() {
+                fs::create_dir_all(dst_parent)?;
+            }
+            // copy symlinks as links, not as the content they point to
+            if fs::symlink_metadata(src)?.is_symlink() {
+                symlink(fs::read_link(src)?, dst)?;
+            } else {
+                fs::copy(src, dst)?;
             }
+        }
+        FsOp::HardLink { src, dst } => {
             if let Some(dst_parent) = dst.parent() {
                 fs::create_dir_all(dst_parent)?;
             }
-            fs::copy(src, dst)?;
+            fs::hard_link(src, dst)?;
+        }
+        FsOp::SetPermissions { path, mode } => {
+            fs::set_permissions(path, fs::Permissions::from_mode(*mode))?;
         }
-        FsOp::RemoveFile { path: path_str } => {
-            // TODO: move to trash
-            let path = Path::new(path_str.as_ref());
-            if !path.exists() {
-                return Err(TreeEditError::FsChanged(DetectedBy::FileNotFound(
-                    path_str.to_string(),
-                )));
+        FsOp::Chown { path, user, group } => {
+            // names were resolved by the check already
+            let uid =
+                owner::uid(user).ok_or_else(|| TreeEditError::UnknownUser(user.to_string()))?;
+            let gid =
+                owner::gid(group).ok_or_else(|| TreeEditError::UnknownGroup(group.to_string()))?;
+            // change the link itself, the target may not even be listed
+            lchown(path, Some(uid), Some(gid))?;
+        }
+        FsOp::RemoveFile { path } => fs::remove_file(path)?,
+        FsOp::TrashFile { path } => {
+            trash::trash(path)?;
+        }
+        // only ever remove empty directories, anything left inside
+        // was not part of the listing and must not be lost
+        FsOp::RemoveDir { path } => fs::remove_dir(path)?,
+        FsOp::PruneDir { path } => {
+            // this is only cleanup, keep the directory if
+            // it still holds files that were not listed
+            match fs::remove_dir(path) {
+                Err(e) if e.kind() == io::ErrorKind::DirectoryNotEmpty => {
+                    eprintln!("keeping non-empty directory {}", path.display())
+                }
+                result => result?,
             }
-            fs::remove_file(path)?;
         }
     }
     Ok(())
 }
 
-pub fn exec_all(ops: &Vec<FsOp>) -> crate::Result<()> {
-    for op in ops {
-        exec(&op)?;
+/// Checks that the file system is in the state an operation expects,
+/// this is what detects changes made while the buffer was being edited
+pub fn check(op: &FsOp) -> crate::Result<()> {
+    check_with(op, &node)
+}
+
+// what is at a path, as far as the checks are concerned
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Node {
+    File,
+    Dir,
+    Symlink,
+}
+
+// does not follow symlinks, a dangling link still exists
+fn node(path: &Path) -> Option<Node> {
+    let metadata = fs::symlink_metadata(path).ok()?;
+    Some(if metadata.is_dir() {
+        Node::Dir
+    } else if metadata.is_symlink() {
+        Node::Symlink
+    } else {
+        Node::File
+    })
+}
+
+fn check_with(op: &FsOp, node: &dyn Fn(&Path) -> Option<Node>) -> crate::Result<()> {
+    let not_found = |path: &Path| {
+        Err(TreeEditError::FsChanged(DetectedBy::FileNotFound(
+            path.to_path_buf(),
+        )))
+    };
+    let exists = |path: &Path| {
+        Err(TreeEditError::FsChanged(DetectedBy::FileExists(
+            path.to_path_buf(),
+        )))
+    };
+    match op {
+        FsOp::CreateFile { path } | FsOp::CreateSymlink { path, .. } => {
+            if node(path).is_some() {
+                return exists(path);
+            }
+            check_parent(path, node)?;
+        }
+        FsOp::CreateDir { path } => {
+            if node(path).is_some_and(|node| node != Node::Dir) {
+                return exists(path);
+            }
+            check_parent(path, node)?;
+        }
+        FsOp::RetargetSymlink { path, .. } => {
+            if node(path) != Some(Node::Symlink) {
+                return not_found(path);
+            }
+        }
+        FsOp::MoveFile { src, dst } | FsOp::CopyFile { src, dst } | FsOp::HardLink { src, dst } => {
+            if node(src).is_none() {
+                return not_found(src);
+            }
+            if node(dst).is_some() {
+                return exists(dst);
+            }
+            check_parent(dst, node)?;
+        }
+        FsOp::MoveDir { src, dst } => {
+            if node(src) != Some(Node::Dir) {
+                return not_found(src);
+            }
+            if node(dst).is_some() {
+                return exists(dst);
+            }
+            check_parent(dst, node)?;
+        }
+        FsOp::Chown { path, user, group } => {
+            if node(path).is_none() {
+                return not_found(path);
+            }
+            if owner::uid(user).is_none() {
+                return Err(TreeEditError::UnknownUser(user.to_string()));
+            }
+            if owner::gid(group).is_none() {
+                return Err(TreeEditError::UnknownGroup(group.to_string()));
+            }
+        }
+        FsOp::SetPermissions { path, .. }
+        | FsOp::RemoveFile { path }
+        | FsOp::TrashFile { path }
+        | FsOp::RemoveDir { path }
+        | FsOp::PruneDir { path } => {
+            if node(path).is_none() {
+                return not_found(path);
+            }
+        }
     }
     Ok(())
 }
+
+// the closest ancestor that exists must not be a file, a symlink
+// may well point to a directory
+fn check_parent(path: &Path, node: &dyn Fn(&Path) -> Option<Node>) -> crate::Result<()> {
+    let parent = path
+        .ancestors()
+        .skip(1)
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .find_map(|dir| Some((dir, node(dir)?)));
+    match parent {
+        Some((dir, Node::File)) => Err(TreeEditError::FsChanged(DetectedBy::NotADirectory(
+            dir.to_path_buf(),
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Checks operations one after the other without touching the file system,
+/// each one against the state the ones before it would have left
+#[derive(Default)]
+pub struct Preflight {
+    changes: Vec<Change>,
+}
+
+enum Change {
+    Created(PathBuf, Node),
+    Removed(PathBuf),
+    Moved(PathBuf, PathBuf),
+}
+
+impl Preflight {
+    pub fn check(&mut self, op: &FsOp) -> crate::Result<()> {
+        check_with(op, &|path| self.node(path))?;
+        match op {
+            FsOp::CreateFile { path } => self.create(path, Node::File),
+            FsOp::CreateDir { path } => self.create(path, Node::Dir),
+            FsOp::CreateSymlink { path, .. } => self.create(path, Node::Symlink),
+            FsOp::MoveFile { src, dst } | FsOp::MoveDir { src, dst } => {
+                self.create_parents(dst);
+                self.changes
+                    .push(Change::Moved(src.to_path_buf(), dst.to_path_buf()));
+            }
+            FsOp::CopyFile { src, dst } | FsOp::HardLink { src, dst } => {
+                let node = self.node(src).unwrap_or(Node::File);
+                self.create(dst, node);
+            }
+            FsOp::RemoveFile { path } | FsOp::TrashFile { path } | FsOp::RemoveDir { path } => {
+                self.changes.push(Change::Removed(path.to_path_buf()))
+            }
+            // whether a directory is pruned depends on what else is in it
+            FsOp::PruneDir { .. }
+            | FsOp::RetargetSymlink { .. }
+            | FsOp::SetPermissions { .. }
+            | FsOp::Chown { .. } => (),
+        }
+        Ok(())
+    }
+
+    fn create(&mut self, path: &Path, node: Node) {
+        self.create_parents(path);
+        self.changes.push(Change::Created(path.to_path_buf(), node));
+    }
+
+    fn create_parents(&mut self, path: &Path) {
+        let missing = path
+            .ancestors()
+            .skip(1)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .take_while(|dir| self.node(dir).is_none())
+            .map(|dir| Change::Created(dir.to_path_buf(), Node::Dir))
+            .collect::<Vec<_>>();
+        self.changes.extend(missing);
+    }
+
+    // replays the changes backwards until one of them tells
+    // what is at the path, the file system has the answer otherwise
+    fn node(&self, path: &Path) -> Option<Node> {
+        let mut path = path.to_path_buf();
+        for change in self.changes.iter().rev() {
+            match change {
+                Change::Created(created, node) if *created == path => return Some(*node),
+                Change::Created(..) => (),
+                Change::Removed(removed) if path.starts_with(removed) => return None,
+                Change::Removed(_) => (),
+                Change::Moved(src, dst) => {
+                    if let Ok(rest) = path.strip_prefix(dst) {
+                        path = match rest.as_os_str().is_empty() {
+                            true => src.clone(),
+                            false => src.join(rest),
+                        };
+                    } else if path.starts_with(src) {
+                        return None;
+                    }
+                }
+            }
+        }
+        node(&path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsutils::tmpfile::TmpDir;
+    use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+
+    #[test]
+    fn test_plan_json() {
+        let plan = Plan {
+            ops: vec![
+                FsOp::MoveFile {
+                    src: PathBuf::from("a b"),
+                    dst: PathBuf::from(OsString::from_vec(b"\xff".to_vec())),
+                },
+                FsOp::SetPermissions {
+                    path: PathBuf::from("c"),
+                    mode: 0o755,
+                },
+            ],
+            ..Plan::default()
+        };
+        let json = serde_json::to_string(&plan).unwrap();
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"ops":[{"op":"move_file","src":"a b","dst":[255]},"#,
+                r#"{"op":"set_permissions","path":"c","mode":"0755"}]}"#
+            )
+        );
+        let parsed = serde_json::from_str::<Plan>(&json).unwrap();
+        assert_eq!(format!("{:?}", parsed), format!("{:?}", plan));
+        assert!(serde_json::from_str::<Plan>(r#"{"ops":[{"op":"chmod"}]}"#).is_err());
+        assert!(serde_json::from_str::<Plan>(
+            r#"{"ops":[{"op":"set_permissions","path":"c","mode":"999"}]}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_plan_json_listed() {
+        let plan = Plan {
+            ops: vec![FsOp::RemoveFile {
+                path: PathBuf::from(OsString::from_vec(b"\xff".to_vec())),
+            }],
+            listed: vec![ListedFile {
+                path: PathBuf::from(OsString::from_vec(b"\xff".to_vec())),
+                fingerprint: Fingerprint {
+                    ino: 1,
+                    size: 2,
+                    mtime: (3, 4),
+                    hash: Some(u64::MAX),
+                },
+            }],
+        };
+        let json = serde_json::to_string(&plan).unwrap();
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"ops":[{"op":"remove_file","path":[255]}],"#,
+                r#""listed":[{"path":[255],"ino":1,"size":2,"mtime":[3,4],"hash":18446744073709551615}]}"#
+            )
+        );
+        let parsed = serde_json::from_str::<Plan>(&json).unwrap();
+        assert_eq!(format!("{:?}", parsed), format!("{:?}", plan));
+    }
+
+    #[test]
+    fn test_preflight() -> crate::Result<()> {
+        let tmp = TmpDir::new("tree-edit-preflight")?;
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("a"))?;
+        fs::write(dir.join("a/x"), "")?;
+        fs::write(dir.join("b"), "")?;
+        let path = |name: &str| dir.join(name);
+        let mut preflight = Preflight::default();
+        let ops = [
+            FsOp::MoveDir {
+                src: path("a"),
+                dst: path("c/a"),
+            },
+            FsOp::MoveFile {
+                src: path("c/a/x"),
+                dst: path("a"),
+            },
+            FsOp::CopyFile {
+                src: path("b"),
+                dst: path("c/a/x"),
+            },
+            FsOp::RemoveFile { path: path("b") },
+            FsOp::CreateFile { path: path("b") },
+        ];
+        for op in &ops {
+            preflight.check(op)?;
+        }
+        let conflicts = [
+            FsOp::CreateDir { path: path("a") },
+            FsOp::CreateFile { path: path("c") },
+            FsOp::MoveDir {
+                src: path("c/a/x"),
+                dst: path("d"),
+            },
+            FsOp::RemoveFile {
+                path: path("c/a/y"),
+            },
+            // a is a file by now
+            FsOp::CreateFile { path: path("a/y") },
+        ];
+        for op in &conflicts {
+            assert!(
+                matches!(preflight.check(op), Err(TreeEditError::FsChanged(_))),
+                "{:?}",
+                op
+            );
+        }
+        // nothing was touched
+        assert!(dir.join("a/x").exists());
+        assert!(!dir.join("c").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_preflight_under_file() -> crate::Result<()> {
+        let tmp = TmpDir::new("tree-edit-preflight-file")?;
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("b/c"))?;
+        fs::write(dir.join("a"), "")?;
+        let path = |name: &str| dir.join(name);
+        // a is only removed after b was moved under it
+        let ops = [
+            FsOp::MoveDir {
+                src: path("b"),
+                dst: path("a/b"),
+            },
+            FsOp::TrashFile { path: path("a") },
+            FsOp::CreateDir { path: path("a") },
+        ];
+        let mut preflight = Preflight::default();
+        assert!(matches!(
+            preflight.check(&ops[0]),
+            Err(TreeEditError::FsChanged(DetectedBy::NotADirectory(file))) if file == path("a")
+        ));
+        assert!(matches!(
+            check(&ops[0]),
+            Err(TreeEditError::FsChanged(DetectedBy::NotADirectory(_)))
+        ));
+        Ok(())
+    }
+}
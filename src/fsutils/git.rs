@@ -0,0 +1,351 @@
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    io::{self, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+use crate::fsutils::fsop::FsOp;
+
+/// Whether the git index follows the operations
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum GitMode {
+    /// When the root directory is inside a git work tree
+    #[default]
+    Auto,
+    /// Always, the root directory must be inside a git work tree
+    Always,
+    Never,
+}
+
+impl FromStr for GitMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(GitMode::Auto),
+            "always" => Ok(GitMode::Always),
+            "never" => Ok(GitMode::Never),
+            _ => Err(format!(
+                "unknown git mode {}, expected auto, always or never",
+                s
+            )),
+        }
+    }
+}
+
+/// Whether a `git status` marker stands for changes that were not committed,
+/// staged or not, untracked files included; ignored files have none
+pub fn is_uncommitted(marker: &str) -> bool {
+    marker != "!!" && marker.trim() != ""
+}
+
+/// A git work tree whose index is kept up to date as files are moved and
+/// removed, the way `git mv` and `git rm` would; untracked files are left alone
+pub struct Git {
+    // the root directory, operation paths are relative to it
+    root: PathBuf,
+    top: PathBuf,
+}
+
+// an index entry, `<mode> <object> <stage>\t<path>` in `git ls-files -s`
+struct IndexEntry {
+    mode: String,
+    object: String,
+    stage: String,
+    path: PathBuf,
+}
+
+impl Git {
+    /// The work tree the root directory is in, if any
+    pub fn discover(root: &Path) -> Option<Git> {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(root)
+            .args(["rev-parse", "--show-toplevel"])
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let top = output.stdout.strip_suffix(b"\n")?;
+        Some(Git {
+            root: root.to_path_buf(),
+            top: PathBuf::from(OsStr::from_bytes(top)),
+        })
+    }
+
+    /// The `git status --short` marker of every changed, untracked or ignored
+    /// path under the root directory, by path relative to it
+    pub fn status(&self) -> io::Result<HashMap<PathBuf, String>> {
+        let prefix = self.index_path(Path::new("")).unwrap_or_default();
+        let output = self
+            .git()
+            .args(["status", "--porcelain", "-z", "--untracked-files=all"])
+            .args(["--ignored=matching", "--"])
+            .arg(match prefix.as_os_str().is_empty() {
+                true => Path::new("."),
+                false => &prefix,
+            })
+            .stderr(Stdio::inherit())
+            .output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!("git status {}", output.status)));
+        }
+        let mut status = HashMap::new();
+        let mut records = output.stdout.split(|&byte| byte == 0);
+        while let Some(record) = records.next() {
+            // `XY path`, paths are relative to the top of the work tree
+            let (Some(marker), Some(path)) = (record.get(..2), record.get(3..)) else {
+                continue;
+            };
+            // renames and copies are followed by the path they came from
+            if marker.contains(&b'R') || marker.contains(&b'C') {
+                records.next();
+            }
+            // ignored directories end with a slash
+            let path = Path::new(OsStr::from_bytes(path.strip_suffix(b"/").unwrap_or(path)));
+            if let Ok(relative) = path.strip_prefix(&prefix) {
+                let marker = String::from_utf8_lossy(marker).into_owned();
+                status.insert(relative.to_path_buf(), marker);
+            }
+        }
+        Ok(status)
+    }
+
+    /// Updates the index after an operation ran
+    pub fn sync(&self, op: &FsOp) -> io::Result<()> {
+        match op {
+            FsOp::MoveFile { src, dst } | FsOp::MoveDir { src, dst } => self.moved(src, dst),
+            FsOp::RemoveFile { path } | FsOp::TrashFile { path } => self.removed(path),
+            // new files are not tracked until added, and changes
+            // to tracked files are for the user to stage
+            _ => Ok(()),
+        }
+    }
+
+    // moves the entries of src over to dst, keeping what was staged for them
+    fn moved(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let (Some(src), Some(dst)) = (self.index_path(src), self.index_path(dst)) else {
+            return Ok(());
+        };
+        // the work tree itself is never moved
+        if src.as_os_str().is_empty() || dst.as_os_str().is_empty() {
+            return Ok(());
+        }
+        let mut index_info = Vec::new();
+        // a conflict is left for the user to resolve
+        for entry in self.entries(&src)?.iter().filter(|e| e.stage == "0") {
+            let moved = match entry.path.strip_prefix(&src) {
+                Ok(relative) if relative.as_os_str().is_empty() => dst.clone(),
+                Ok(relative) => dst.join(relative),
+                Err(_) => continue,
+            };
+            // mode 0 removes an entry
+            let removed = format!("0 {}\t", "0".repeat(entry.object.len()));
+            index_info.extend_from_slice(removed.as_bytes());
+            index_info.extend_from_slice(entry.path.as_os_str().as_bytes());
+            index_info.push(0);
+            index_info.extend_from_slice(format!("{} {}\t", entry.mode, entry.object).as_bytes());
+            index_info.extend_from_slice(moved.as_os_str().as_bytes());
+            index_info.push(0);
+        }
+        if index_info.is_empty() {
+            return Ok(());
+        }
+        self.update_index(&["-z", "--index-info"], &index_info)
+    }
+
+    fn removed(&self, path: &Path) -> io::Result<()> {
+        let Some(path) = self.index_path(path).filter(|p| !p.as_os_str().is_empty()) else {
+            return Ok(());
+        };
+        let mut paths = Vec::new();
+        for entry in self.entries(&path)? {
+            paths.extend_from_slice(entry.path.as_os_str().as_bytes());
+            paths.push(0);
+        }
+        if paths.is_empty() {
+            return Ok(());
+        }
+        self.update_index(&["-z", "--force-remove", "--stdin"], &paths)
+    }
+
+    // the path relative to the top of the work tree, if it is inside of it
+    fn index_path(&self, path: &Path) -> Option<PathBuf> {
+        let path = crate::normalize(&self.root.join(path));
+        Some(path.strip_prefix(&self.top).ok()?.to_path_buf())
+    }
+
+    fn entries(&self, path: &Path) -> io::Result<Vec<IndexEntry>> {
+        let output = self
+            .git()
+            .args(["ls-files", "--stage", "-z", "--"])
+            .arg(path)
+            .stderr(Stdio::inherit())
+            .output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!("git ls-files {}", output.status)));
+        }
+        let entries = output
+            .stdout
+            .split(|&byte| byte == 0)
+            .filter_map(|record| {
+                let tab = record.iter().position(|&byte| byte == b'\t')?;
+                let info = std::str::from_utf8(&record[..tab]).ok()?;
+                let mut fields = info.split(' ');
+                Some(IndexEntry {
+                    mode: fields.next()?.to_string(),
+                    object: fields.next()?.to_string(),
+                    stage: fields.next()?.to_string(),
+                    path: PathBuf::from(OsStr::from_bytes(&record[tab + 1..])),
+                })
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    fn update_index(&self, args: &[&str], input: &[u8]) -> io::Result<()> {
+        let mut child = self
+            .git()
+            .arg("update-index")
+            .args(args)
+            .stdin(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(input)?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("git update-index {}", status)));
+        }
+        Ok(())
+    }
+
+    // paths are given relative to the top of the work tree, and taken literally
+    fn git(&self) -> Command {
+        let mut command = Command::new("git");
+        command.arg("--literal-pathspecs").arg("-C").arg(&self.top);
+        command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsutils::tmpfile::TmpDir;
+    use std::{env, fs};
+
+    fn git(dir: &Path, args: &[&str]) -> io::Result<Vec<u8>> {
+        let output = Command::new("git").arg("-C").arg(dir).args(args).output()?;
+        assert!(output.status.success(), "{:?}", output);
+        Ok(output.stdout)
+    }
+
+    #[test]
+    fn test_sync_index() -> io::Result<()> {
+        let tmp = TmpDir::new("tree-edit-git")?;
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("sub/d"))?;
+        git(dir, &["init", "-q"])?;
+        for name in ["sub/a", "sub/d/b", "sub/c*", "sub/gone"] {
+            fs::write(dir.join(name), name)?;
+        }
+        git(dir, &["add", "sub"])?;
+        // unstaged changes stay unstaged once moved
+        fs::write(dir.join("sub/a"), "changed")?;
+        fs::write(dir.join("sub/untracked"), "")?;
+        let root = dir.join("sub");
+        let git_dir = Git::discover(&root).unwrap();
+        let ops = [
+            FsOp::MoveFile {
+                src: PathBuf::from("a"),
+                dst: PathBuf::from("../moved"),
+            },
+            FsOp::MoveDir {
+                src: PathBuf::from("d"),
+                dst: PathBuf::from("e"),
+            },
+            FsOp::MoveFile {
+                src: PathBuf::from("c*"),
+                dst: PathBuf::from("c"),
+            },
+            FsOp::MoveFile {
+                src: PathBuf::from("untracked"),
+                dst: PathBuf::from("still untracked"),
+            },
+            FsOp::RemoveFile {
+                path: PathBuf::from("gone"),
+            },
+        ];
+        for op in &ops {
+            match op {
+                FsOp::MoveFile { src, dst } | FsOp::MoveDir { src, dst } => {
+                    fs::rename(root.join(src), root.join(dst))?
+                }
+                FsOp::RemoveFile { path } => fs::remove_file(root.join(path))?,
+                _ => unreachable!(),
+            }
+            git_dir.sync(op)?;
+        }
+        let index = git(dir, &["ls-files"])?;
+        assert_eq!(String::from_utf8_lossy(&index), "moved\nsub/c\nsub/e/b\n");
+        let status = git(dir, &["status", "--porcelain"])?;
+        assert_eq!(
+            String::from_utf8_lossy(&status),
+            "AM moved\nA  sub/c\nA  sub/e/b\n?? \"sub/still untracked\"\n"
+        );
+        assert!(Git::discover(&env::temp_dir()).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_status() -> io::Result<()> {
+        let tmp = TmpDir::new("tree-edit-git-status")?;
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("sub"))?;
+        git(dir, &["init", "-q"])?;
+        for name in [
+            "outside",
+            "sub/clean",
+            "sub/changed",
+            "sub/staged",
+            "sub/.gitignore",
+        ] {
+            fs::write(dir.join(name), name)?;
+        }
+        git(dir, &["add", "."])?;
+        let commit = [
+            "-c",
+            "user.name=a",
+            "-c",
+            "user.email=a@b",
+            "commit",
+            "-qm",
+            "a",
+        ];
+        git(dir, &commit)?;
+        fs::write(dir.join("outside"), "changed")?;
+        fs::write(dir.join("sub/changed"), "changed")?;
+        fs::write(dir.join("sub/staged"), "changed")?;
+        git(dir, &["add", "sub/staged"])?;
+        fs::write(dir.join("sub/.gitignore"), "ignored\n")?;
+        fs::write(dir.join("sub/ignored"), "")?;
+        fs::write(dir.join("sub/new"), "")?;
+        let status = Git::discover(&dir.join("sub")).unwrap().status()?;
+        let marker = |path: &str| status.get(Path::new(path)).map(String::as_str);
+        assert_eq!(marker("changed"), Some(" M"));
+        assert_eq!(marker("staged"), Some("M "));
+        assert_eq!(marker("new"), Some("??"));
+        assert_eq!(marker("ignored"), Some("!!"));
+        assert_eq!(marker("clean"), None);
+        assert_eq!(status.len(), 5, "{:?}", status);
+        assert!(is_uncommitted(" M") && is_uncommitted("M ") && is_uncommitted("D "));
+        assert!(is_uncommitted("??") && !is_uncommitted("!!"));
+        Ok(())
+    }
+}
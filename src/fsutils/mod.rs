@@ -1,2 +1,9 @@
+pub mod fingerprint;
 pub mod fsop;
+pub mod git;
+pub mod owner;
+pub mod shell;
+pub mod time;
 pub mod tmpfile;
+pub mod trash;
+pub mod xdg;
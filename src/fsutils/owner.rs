@@ -0,0 +1,61 @@
+use std::ffi::{CStr, CString};
+
+// the lookups below go through libc so that users from NSS sources
+// (LDAP and the like) resolve the same way they do for `ls` and `chown`
+
+enum Query<'a> {
+    User(u32),
+    UserNamed(&'a CStr),
+    Group(u32),
+    GroupNamed(&'a CStr),
+}
+
+// the name and id of the matching user or group, if any
+fn lookup(query: Query) -> Option<(String, u32)> {
+    // SAFETY: names passed in are nul-terminated; each lookup returns null or
+    // a record owned by libc that stays valid until the next lookup, whose name
+    // is a nul-terminated string; both are copied out before returning
+    unsafe {
+        let (name, id) = match query {
+            Query::User(uid) => libc::getpwuid(uid)
+                .as_ref()
+                .map(|p| (p.pw_name, p.pw_uid))?,
+            Query::UserNamed(name) => libc::getpwnam(name.as_ptr())
+                .as_ref()
+                .map(|p| (p.pw_name, p.pw_uid))?,
+            Query::Group(gid) => libc::getgrgid(gid)
+                .as_ref()
+                .map(|g| (g.gr_name, g.gr_gid))?,
+            Query::GroupNamed(name) => libc::getgrnam(name.as_ptr())
+                .as_ref()
+                .map(|g| (g.gr_name, g.gr_gid))?,
+        };
+        Some((CStr::from_ptr(name).to_string_lossy().into_owned(), id))
+    }
+}
+
+pub fn user_name(uid: u32) -> String {
+    lookup(Query::User(uid)).map_or_else(|| uid.to_string(), |(name, _)| name)
+}
+
+pub fn group_name(gid: u32) -> String {
+    lookup(Query::Group(gid)).map_or_else(|| gid.to_string(), |(name, _)| name)
+}
+
+/// Resolves a user name, or a numeric uid as-is
+pub fn uid(name: &str) -> Option<u32> {
+    let c_name = CString::new(name).ok()?;
+    match lookup(Query::UserNamed(&c_name)) {
+        Some((_, uid)) => Some(uid),
+        None => name.parse().ok(),
+    }
+}
+
+/// Resolves a group name, or a numeric gid as-is
+pub fn gid(name: &str) -> Option<u32> {
+    let c_name = CString::new(name).ok()?;
+    match lookup(Query::GroupNamed(&c_name)) {
+        Some((_, gid)) => Some(gid),
+        None => name.parse().ok(),
+    }
+}
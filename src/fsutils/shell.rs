@@ -0,0 +1,207 @@
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::Path};
+
+use crate::fsutils::fsop::FsOp;
+
+// the guards mirror `fsop::check`, a dangling symlink still exists
+const PRELUDE: &str = r#"#!/bin/sh
+# generated by tree-edit, run it from the directory it was made for
+set -e
+
+exists() { [ -e "$1" ] || [ -L "$1" ]; }
+changed() { printf 'file system changed: %s %s\n' "$1" "$2" >&2; exit 1; }
+need() { exists "$1" || changed 'file not found' "$1"; }
+need_dir() { [ -d "$1" ] && [ ! -L "$1" ] || changed 'file not found' "$1"; }
+need_link() { [ -L "$1" ] || changed 'file not found' "$1"; }
+free() { ! exists "$1" || changed 'file already exists' "$1"; }
+free_or_dir() { ! exists "$1" || need_dir "$1"; }
+"#;
+
+/// Renders operations as a POSIX shell script doing the same, stopping
+/// at the first one the file system is not ready for
+pub fn script(ops: &[FsOp]) -> Vec<u8> {
+    let mut script = PRELUDE.as_bytes().to_vec();
+    if ops.iter().any(|op| matches!(op, FsOp::TrashFile { .. })) {
+        script.extend_from_slice(b"# there is no portable trash, trashed files are removed\n");
+    }
+    script.push(b'\n');
+    for op in ops {
+        for line in lines(op) {
+            script.extend(line);
+            script.push(b'\n');
+        }
+    }
+    script
+}
+
+fn lines(op: &FsOp) -> Vec<Vec<u8>> {
+    let line = |words: &[&[u8]]| words.join(&b' ');
+    let q = |path: &Path| quote(path.as_os_str());
+    // mkdir -p for the parent, if there is one
+    let parent = |path: &Path| match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => {
+            vec![line(&[b"mkdir -p --", &q(parent)])]
+        }
+        _ => Vec::new(),
+    };
+    match op {
+        FsOp::CreateFile { path } => [
+            vec![line(&[b"free", &q(path)])],
+            parent(path),
+            vec![line(&[b":", b">", &q(path)])],
+        ]
+        .concat(),
+        FsOp::CreateDir { path } => vec![
+            line(&[b"free_or_dir", &q(path)]),
+            line(&[b"mkdir -p --", &q(path)]),
+        ],
+        FsOp::CreateSymlink { path, target } => [
+            vec![line(&[b"free", &q(path)])],
+            parent(path),
+            vec![line(&[b"ln -s --", &q(target), &q(path)])],
+        ]
+        .concat(),
+        FsOp::RetargetSymlink { path, target } => vec![
+            line(&[b"need_link", &q(path)]),
+            line(&[b"rm --", &q(path)]),
+            line(&[b"ln -s --", &q(target), &q(path)]),
+        ],
+        FsOp::MoveFile { src, dst } | FsOp::MoveDir { src, dst } => {
+            let need: &[u8] = match op {
+                FsOp::MoveDir { .. } => b"need_dir",
+                _ => b"need",
+            };
+            [
+                vec![line(&[need, &q(src)]), line(&[b"free", &q(dst)])],
+                parent(dst),
+                vec![line(&[b"mv -n --", &q(src), &q(dst)])],
+            ]
+            .concat()
+        }
+        // -P -R copies a symlink as a link, like `fsop::exec`
+        FsOp::CopyFile { src, dst } => [
+            vec![line(&[b"need", &q(src)]), line(&[b"free", &q(dst)])],
+            parent(dst),
+            vec![line(&[b"cp -n -P -R --", &q(src), &q(dst)])],
+        ]
+        .concat(),
+        FsOp::HardLink { src, dst } => [
+            vec![line(&[b"need", &q(src)]), line(&[b"free", &q(dst)])],
+            parent(dst),
+            vec![line(&[b"ln --", &q(src), &q(dst)])],
+        ]
+        .concat(),
+        FsOp::SetPermissions { path, mode } => vec![
+            line(&[b"need", &q(path)]),
+            line(&[b"chmod --", format!("{:04o}", mode).as_bytes(), &q(path)]),
+        ],
+        FsOp::Chown { path, user, group } => {
+            let owner = quote(OsStr::new(&format!("{}:{}", user, group)));
+            vec![
+                line(&[b"need", &q(path)]),
+                line(&[b"chown -h --", &owner, &q(path)]),
+            ]
+        }
+        FsOp::RemoveFile { path } | FsOp::TrashFile { path } => {
+            vec![line(&[b"need", &q(path)]), line(&[b"rm --", &q(path)])]
+        }
+        FsOp::RemoveDir { path } => {
+            vec![line(&[b"need", &q(path)]), line(&[b"rmdir --", &q(path)])]
+        }
+        // keep the directory if it still holds files that were not listed
+        FsOp::PruneDir { path } => vec![
+            line(&[b"need", &q(path)]),
+            [
+                line(&[b"rmdir --", &q(path), b"2>/dev/null ||"]),
+                line(&[
+                    b"printf 'keeping non-empty directory %s\\n'",
+                    &q(path),
+                    b">&2",
+                ]),
+            ]
+            .join(&b' '),
+        ],
+    }
+}
+
+// single quotes keep every byte as is, except the quote itself
+fn quote(s: &OsStr) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &byte in s.as_bytes() {
+        match byte {
+            b'\'' => quoted.extend_from_slice(b"'\\''"),
+            _ => quoted.push(byte),
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsutils::tmpfile::TmpDir;
+    use std::{fs, io, path::PathBuf, process};
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote(OsStr::new("a b")), b"'a b'");
+        assert_eq!(quote(OsStr::new("it's")), b"'it'\\''s'");
+        assert_eq!(quote(OsStr::from_bytes(b"\xff\n")), b"'\xff\n'");
+    }
+
+    #[test]
+    fn test_run_script() -> io::Result<()> {
+        let tmp = TmpDir::new("tree-edit-shell")?;
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("d"))?;
+        fs::write(dir.join("a"), "a")?;
+        fs::write(dir.join("d/it's"), "b")?;
+        fs::write(dir.join("-x"), "x")?;
+        let ops = vec![
+            FsOp::MoveFile {
+                src: PathBuf::from("a"),
+                dst: PathBuf::from("new dir/a"),
+            },
+            FsOp::CopyFile {
+                src: PathBuf::from("d/it's"),
+                dst: PathBuf::from("c"),
+            },
+            FsOp::RemoveFile {
+                path: PathBuf::from("d/it's"),
+            },
+            FsOp::PruneDir {
+                path: PathBuf::from("d"),
+            },
+            FsOp::CreateSymlink {
+                path: PathBuf::from("l"),
+                target: PathBuf::from("-x"),
+            },
+            FsOp::SetPermissions {
+                path: PathBuf::from("-x"),
+                mode: 0o600,
+            },
+        ];
+        let script_path = dir.join("script.sh");
+        fs::write(&script_path, script(&ops))?;
+        let run = || {
+            process::Command::new("sh")
+                .arg(&script_path)
+                .current_dir(dir)
+                .output()
+        };
+        let output = run()?;
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(fs::read_to_string(dir.join("new dir/a"))?, "a");
+        assert_eq!(fs::read_to_string(dir.join("c"))?, "b");
+        assert!(fs::symlink_metadata(dir.join("d")).is_err());
+        assert_eq!(fs::read_link(dir.join("l"))?, Path::new("-x"));
+        // the guards stop the script before anything is done twice
+        let output = run()?;
+        assert!(!output.status.success());
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr),
+            "file system changed: file not found a\n"
+        );
+        Ok(())
+    }
+}
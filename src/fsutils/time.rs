@@ -0,0 +1,15 @@
+use std::mem;
+
+/// Seconds since the epoch broken down in the local time zone,
+/// none if they cannot be represented
+pub fn local_time(secs: i64) -> Option<libc::tm> {
+    // SAFETY: tm is plain data, localtime_r only reads time and writes tm
+    // through pointers valid for the call; tm is fully written on success
+    // and never read otherwise
+    let mut tm = unsafe { mem::zeroed::<libc::tm>() };
+    let time = secs as libc::time_t;
+    match unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        true => None,
+        false => Some(tm),
+    }
+}
@@ -1,5 +1,8 @@
 use std::{env, fs, io, path::PathBuf};
 
+#[cfg(test)]
+use std::path::Path;
+
 use rand::{distributions::Alphanumeric, Rng};
 
 pub struct TmpFile {
@@ -26,6 +29,38 @@ impl Drop for TmpFile {
     }
 }
 
+/// A directory removed along with its content when dropped, even if a test fails
+#[cfg(test)]
+pub struct TmpDir {
+    path: PathBuf,
+}
+
+#[cfg(test)]
+impl TmpDir {
+    pub fn new(prefix: &str) -> io::Result<TmpDir> {
+        TmpDir::new_in(&env::temp_dir(), prefix)
+    }
+
+    pub fn new_in(parent: &Path, prefix: &str) -> io::Result<TmpDir> {
+        let path = parent.join(format!("{}-{}", prefix, get_tmp_file_name()));
+        fs::create_dir_all(&path)?;
+        // the temporary directory may be behind a symlink
+        let path = fs::canonicalize(path)?;
+        Ok(TmpDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+impl Drop for TmpDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
 pub fn get_tmp_file_name() -> String {
     rand::thread_rng()
         .sample_iter(&Alphanumeric)
@@ -0,0 +1,210 @@
+use std::{
+    fs,
+    io::{self, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{DirBuilderExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::fsutils::{time, xdg};
+
+// implements https://specifications.freedesktop.org/trash-spec/latest/
+
+/// Moves a file to the trash of the file system it lives on,
+/// returning where it ended up
+pub fn trash(path: &Path) -> io::Result<PathBuf> {
+    let trashed = reserve(path)?;
+    put(path, &trashed)?;
+    Ok(trashed)
+}
+
+/// Picks where a file goes in the trash and writes its info file,
+/// the file itself is moved there by `put`
+pub fn reserve(path: &Path) -> io::Result<PathBuf> {
+    let path = absolute(path)?;
+    let dev = fs::symlink_metadata(&path)?.dev();
+    let home_trash = home_trash_dir()?;
+    let trash_dir = if device_of(&home_trash)? == dev {
+        home_trash
+    } else {
+        mount_trash_dir(&path, dev)?
+    };
+    reserve_in(&path, &trash_dir)
+}
+
+/// Moves a file to the place reserved for it in the trash
+pub fn put(path: &Path, trashed: &Path) -> io::Result<()> {
+    fs::rename(path, trashed).inspect_err(|_| {
+        if let Some(info_path) = info_path(trashed) {
+            let _ = fs::remove_file(info_path);
+        }
+    })
+}
+
+/// The info file written along a trashed file
+pub fn info_path(trashed: &Path) -> Option<PathBuf> {
+    let mut info_name = trashed.file_name()?.to_os_string();
+    info_name.push(".trashinfo");
+    Some(trashed.parent()?.parent()?.join("info").join(info_name))
+}
+
+fn reserve_in(path: &Path, trash_dir: &Path) -> io::Result<PathBuf> {
+    let files_dir = trash_dir.join("files");
+    let info_dir = trash_dir.join("info");
+    create_private_dir(&files_dir)?;
+    create_private_dir(&info_dir)?;
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "nothing to trash"))?;
+    let info = format!(
+        "[Trash Info]\nPath={}\nDeletionDate={}\n",
+        percent_encode(path),
+        now()?
+    );
+    // creating the info file first reserves the name in the trash
+    for i in 1.. {
+        let mut trashed_name = name.to_os_string();
+        if i > 1 {
+            trashed_name.push(format!(".{}", i));
+        }
+        let mut info_name = trashed_name.clone();
+        info_name.push(".trashinfo");
+        let info_path = info_dir.join(info_name);
+        let files_path = files_dir.join(&trashed_name);
+        if fs::symlink_metadata(&files_path).is_ok() {
+            continue;
+        }
+        let mut info_file = match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&info_path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        };
+        info_file.write_all(info.as_bytes())?;
+        return Ok(files_path);
+    }
+    unreachable!()
+}
+
+fn home_trash_dir() -> io::Result<PathBuf> {
+    Ok(xdg::base_dir("XDG_DATA_HOME", ".local/share")?.join("Trash"))
+}
+
+// `$topdir/.Trash/$uid` if the administrator set it up, `$topdir/.Trash-$uid` otherwise
+fn mount_trash_dir(path: &Path, dev: u64) -> io::Result<PathBuf> {
+    let mut top_dir = path.parent().unwrap_or(path);
+    while let Some(parent) = top_dir.parent() {
+        if fs::metadata(parent)?.dev() != dev {
+            break;
+        }
+        top_dir = parent;
+    }
+    // SAFETY: getuid cannot fail
+    let uid = unsafe { libc::getuid() };
+    let shared = top_dir.join(".Trash");
+    let usable = fs::symlink_metadata(&shared)
+        .is_ok_and(|m| m.is_dir() && !m.is_symlink() && m.mode() & 0o1000 != 0);
+    if usable {
+        let dir = shared.join(uid.to_string());
+        if create_private_dir(&dir).is_ok() {
+            return Ok(dir);
+        }
+    }
+    let dir = top_dir.join(format!(".Trash-{}", uid));
+    create_private_dir(&dir)?;
+    Ok(dir)
+}
+
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+}
+
+// the device of a path, or of its closest existing ancestor
+fn device_of(path: &Path) -> io::Result<u64> {
+    for ancestor in path.ancestors() {
+        if let Ok(metadata) = fs::metadata(ancestor) {
+            return Ok(metadata.dev());
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound, "no such directory"))
+}
+
+// resolves the parent directory but not the file itself, which may be a symlink
+fn absolute(path: &Path) -> io::Result<PathBuf> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "nothing to trash"))?;
+    Ok(fs::canonicalize(parent)?.join(name))
+}
+
+fn percent_encode(path: &Path) -> String {
+    let mut encoded = String::new();
+    for &byte in path.as_os_str().as_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// local time, as the spec asks for
+fn now() -> io::Result<String> {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let tm = time::local_time(secs).ok_or_else(|| io::Error::other("no local time"))?;
+    Ok(format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsutils::tmpfile::TmpDir;
+
+    #[test]
+    fn test_reserve_and_put() -> io::Result<()> {
+        let tmp = TmpDir::new("tree-edit-trash")?;
+        let dir = tmp.path();
+        let trash_dir = dir.join("Trash");
+        for content in ["first", "second"] {
+            let path = dir.join("my file%.txt");
+            fs::write(&path, content)?;
+            put(&path, &reserve_in(&path, &trash_dir)?)?;
+            assert!(fs::symlink_metadata(&path).is_err());
+        }
+        let files = trash_dir.join("files");
+        assert_eq!(fs::read_to_string(files.join("my file%.txt"))?, "first");
+        assert_eq!(fs::read_to_string(files.join("my file%.txt.2"))?, "second");
+        let info_path = info_path(&files.join("my file%.txt.2")).unwrap();
+        assert_eq!(info_path, trash_dir.join("info/my file%.txt.2.trashinfo"));
+        let info = fs::read_to_string(info_path)?;
+        let expected_path = format!("Path={}/my%20file%25.txt\n", dir.display());
+        assert!(info.starts_with("[Trash Info]\n"));
+        assert!(info.contains(&expected_path), "{}", info);
+        Ok(())
+    }
+}
@@ -0,0 +1,13 @@
+use std::{env, io, path::PathBuf};
+
+/// An XDG base directory, from its environment variable if set,
+/// relative to the home directory otherwise
+pub fn base_dir(var: &str, default: &str) -> io::Result<PathBuf> {
+    match env::var_os(var) {
+        Some(dir) if !dir.is_empty() => Ok(PathBuf::from(dir)),
+        _ => match env::var_os("HOME") {
+            Some(home) => Ok(PathBuf::from(home).join(default)),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no home directory")),
+        },
+    }
+}
@@ -0,0 +1,1126 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, Write},
+    os::unix::fs::{symlink, DirBuilderExt, MetadataExt},
+    path::{Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::error::TreeEditError;
+use crate::fsutils::{
+    fsop::{self, FsOp},
+    git::Git,
+    owner, trash, xdg,
+};
+use crate::ui::{quote, unquote};
+
+// every applied plan is a session, a directory under the state directory holding
+// a journal of the operations with how to revert each of them, and a backup of
+// the files that were removed
+//
+// the journal is a text file, one record per line:
+//   root "/path/to/root"
+//   kind edit                 or `kind undo`, then `undoes "/path/to/session"`
+//   plan move-file "a" "b"    every operation, before any of them runs
+//   op 0 move-file "a" "b"    an operation, counted from 0, about to run
+//   undo move-file "b" "a"    along with what reverts it
+//   ok 0                      the operation ran
+//   reverted 0                the operation was rolled back after a failure
+//   done                      or `undone` after a rollback, `failed` if it failed too
+//
+// every line is synced to disk before going on, so that a session cut short
+// can be finished or rolled back with resume
+
+/// Number of sessions kept, older finished ones are deleted along with their backups
+const MAX_SESSIONS: usize = 20;
+
+const JOURNAL: &str = "journal";
+
+/// Where sessions are kept, `$XDG_STATE_HOME/tree-edit` or `~/.local/state/tree-edit`
+pub fn state_dir() -> io::Result<PathBuf> {
+    Ok(xdg::base_dir("XDG_STATE_HOME", ".local/state")?.join("tree-edit"))
+}
+
+pub struct Journal {
+    dir: PathBuf,
+    file: File,
+    backup_count: usize,
+    undoes: Option<PathBuf>,
+    git: Option<Git>,
+}
+
+// how an operation is carried out once its record is written
+enum Action {
+    Exec,
+    Backup { path: PathBuf, backup: PathBuf },
+    Restore { backup: PathBuf, path: PathBuf },
+    Trash { path: PathBuf, trashed: PathBuf },
+}
+
+impl Journal {
+    /// Starts a new session for the given root directory,
+    /// reverting `undoes` if given
+    pub fn create(state_dir: &Path, root: &Path, undoes: Option<&Session>) -> io::Result<Journal> {
+        let sessions_dir = state_dir.join("sessions");
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&sessions_dir)?;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        // zero padded so that sessions sort by name in the order they were made
+        let dir = sessions_dir.join(format!("{:020}-{}", nanos, process::id()));
+        fs::create_dir(&dir)?;
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(dir.join(JOURNAL))?;
+        file.lock()?;
+        let root = fs::canonicalize(root)?;
+        let mut journal = Journal {
+            dir,
+            file,
+            backup_count: 0,
+            undoes: undoes.map(|session| session.dir.clone()),
+            git: None,
+        };
+        let mut header = format!("root {}\n", quote(root.as_os_str()));
+        match &journal.undoes {
+            Some(session_dir) => header.push_str(&format!(
+                "kind undo\nundoes {}\n",
+                quote(session_dir.as_os_str())
+            )),
+            None => header.push_str("kind edit\n"),
+        }
+        journal.write(&header)?;
+        prune_sessions(&sessions_dir)?;
+        Ok(journal)
+    }
+
+    /// Keeps the index of a git work tree up to date as operations run
+    pub fn with_git(mut self, git: Option<Git>) -> Journal {
+        self.git = git;
+        self
+    }
+
+    /// Applies all operations or, when one of them fails, none of them
+    pub fn exec_all(&mut self, ops: &[FsOp]) -> crate::Result<()> {
+        let plan = ops
+            .iter()
+            .map(|op| format!("plan {}\n", format_op(op)))
+            .collect::<String>();
+        self.write(&plan)?;
+        self.run(ops, 0, Vec::new())
+    }
+
+    fn run(
+        &mut self,
+        ops: &[FsOp],
+        start: usize,
+        mut done: Vec<(usize, Vec<FsOp>)>,
+    ) -> crate::Result<()> {
+        for (i, op) in (start..).zip(ops) {
+            match self.exec(i, op) {
+                Ok(undo) => done.push((i, undo)),
+                Err(error) => {
+                    let rollback_errors = self.rollback(done)?;
+                    return Err(TreeEditError::ApplyFailed(Box::new(error), rollback_errors));
+                }
+            }
+        }
+        self.write("done\n")?;
+        if let Some(session_dir) = &self.undoes {
+            mark_undone(session_dir)?;
+        }
+        Ok(())
+    }
+
+    // reverts operations in the reverse order they were applied, going on past
+    // failures; operations that could not be reverted are left for undo to retry
+    fn rollback(&mut self, done: Vec<(usize, Vec<FsOp>)>) -> io::Result<Vec<TreeEditError>> {
+        let mut errors = Vec::new();
+        for (i, undo) in done.iter().rev() {
+            let before = errors.len();
+            for op in undo {
+                if let Err(error) = self
+                    .prepare(op)
+                    .and_then(|(_, action)| self.perform(op, action))
+                {
+                    errors.push(error);
+                }
+            }
+            if errors.len() == before {
+                self.write(&format!("reverted {}\n", i))?;
+            }
+        }
+        self.write(if errors.is_empty() {
+            "undone\n"
+        } else {
+            "failed\n"
+        })?;
+        Ok(errors)
+    }
+
+    // records an operation with what reverts it, then applies it
+    fn exec(&mut self, i: usize, op: &FsOp) -> crate::Result<Vec<FsOp>> {
+        let (undo, action) = self.prepare(op)?;
+        let mut record = format!("op {} {}\n", i, format_op(op));
+        for op in &undo {
+            record.push_str(&format!("undo {}\n", format_op(op)));
+        }
+        self.write(&record)?;
+        if let Err(error) = self.perform(op, action) {
+            // directories may have been created before the failure
+            for op in undo.iter().filter(|op| matches!(op, FsOp::PruneDir { .. })) {
+                let _ = fsop::exec(op);
+            }
+            return Err(error);
+        }
+        self.write(&format!("ok {}\n", i))?;
+        Ok(undo)
+    }
+
+    fn prepare(&mut self, op: &FsOp) -> crate::Result<(Vec<FsOp>, Action)> {
+        fsop::check(op)?;
+        let prepared = match op {
+            // removed files go to the backup area so they can be brought back
+            FsOp::RemoveFile { path } => {
+                self.backup_count += 1;
+                let backup = self.dir.join("backup").join(self.backup_count.to_string());
+                let undo = vec![FsOp::MoveFile {
+                    src: backup.clone(),
+                    dst: path.to_path_buf(),
+                }];
+                let action = Action::Backup {
+                    path: path.to_path_buf(),
+                    backup,
+                };
+                (undo, action)
+            }
+            FsOp::TrashFile { path } => {
+                let trashed = trash::reserve(path)?;
+                let mut undo = vec![FsOp::MoveFile {
+                    src: trashed.clone(),
+                    dst: path.to_path_buf(),
+                }];
+                undo.extend(trash::info_path(&trashed).map(|path| FsOp::RemoveFile { path }));
+                let action = Action::Trash {
+                    path: path.to_path_buf(),
+                    trashed,
+                };
+                (undo, action)
+            }
+            // the backup area may be on another file system than the file
+            FsOp::MoveFile { src, dst } if self.is_backup(src) => {
+                let action = Action::Restore {
+                    backup: src.to_path_buf(),
+                    path: dst.to_path_buf(),
+                };
+                (inverse(op), action)
+            }
+            _ => (inverse(op), Action::Exec),
+        };
+        Ok(prepared)
+    }
+
+    fn perform(&mut self, op: &FsOp, action: Action) -> crate::Result<()> {
+        match action {
+            Action::Backup { path, backup } => move_across_devices(&path, &backup)?,
+            Action::Restore { backup, path } => move_across_devices(&backup, &path)?,
+            Action::Trash { path, trashed } => trash::put(&path, &trashed)?,
+            Action::Exec => fsop::exec(op)?,
+        }
+        // the change itself went through, a stale index is only worth a warning
+        if let Some(git) = &self.git {
+            if let Err(error) = git.sync(op) {
+                eprintln!("could not update the git index: {}", error);
+            }
+        }
+        Ok(())
+    }
+
+    // whether a path is a removed file kept in the backup of a session
+    fn is_backup(&self, path: &Path) -> bool {
+        let session_dir = path
+            .parent()
+            .filter(|dir| dir.file_name() == Some(OsStr::new("backup")))
+            .and_then(Path::parent);
+        session_dir.and_then(Path::parent) == self.dir.parent()
+    }
+
+    fn write(&mut self, lines: &str) -> io::Result<()> {
+        self.file.write_all(lines.as_bytes())?;
+        self.file.sync_data()
+    }
+}
+
+fn mark_undone(session_dir: &Path) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(session_dir.join(JOURNAL))?;
+    file.write_all(b"undone\n")?;
+    file.sync_data()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Status {
+    Running,
+    Done,
+    Undone,
+    Failed,
+}
+
+struct Record {
+    undo: Vec<FsOp>,
+    ok: bool,
+    reverted: bool,
+}
+
+/// A session read back from its journal
+pub struct Session {
+    dir: PathBuf,
+    root: PathBuf,
+    undo_session: bool,
+    plan: Vec<FsOp>,
+    records: BTreeMap<usize, Record>,
+    status: Status,
+}
+
+impl Session {
+    fn load(dir: PathBuf) -> io::Result<Session> {
+        let content = fs::read_to_string(dir.join(JOURNAL))?;
+        let mut session = Session {
+            dir,
+            root: PathBuf::new(),
+            undo_session: false,
+            plan: Vec::new(),
+            records: BTreeMap::new(),
+            status: Status::Running,
+        };
+        let mut last_record = None;
+        for line in content.lines() {
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "invalid journal line in {}: {}",
+                        session.dir.display(),
+                        line
+                    ),
+                )
+            };
+            let index = |s: &str| s.parse::<usize>().map_err(|_| invalid());
+            let (key, rest) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "root" => session.root = PathBuf::from(unquote(rest).ok_or_else(invalid)?.0),
+                "kind" => session.undo_session = rest == "undo",
+                "undoes" => (),
+                "plan" => session.plan.push(parse_op(rest).ok_or_else(invalid)?),
+                "op" => {
+                    let (i, _) = rest.split_once(' ').ok_or_else(invalid)?;
+                    let i = index(i)?;
+                    // an operation that did not go through is recorded again on resume
+                    let record = Record {
+                        undo: Vec::new(),
+                        ok: false,
+                        reverted: false,
+                    };
+                    session.records.insert(i, record);
+                    last_record = Some(i);
+                }
+                "undo" => session
+                    .records
+                    .get_mut(&last_record.ok_or_else(invalid)?)
+                    .ok_or_else(invalid)?
+                    .undo
+                    .push(parse_op(rest).ok_or_else(invalid)?),
+                "ok" => {
+                    session
+                        .records
+                        .get_mut(&index(rest)?)
+                        .ok_or_else(invalid)?
+                        .ok = true
+                }
+                "reverted" => {
+                    session
+                        .records
+                        .get_mut(&index(rest)?)
+                        .ok_or_else(invalid)?
+                        .reverted = true
+                }
+                "done" => session.status = Status::Done,
+                "undone" => session.status = Status::Undone,
+                "failed" => session.status = Status::Failed,
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(session)
+    }
+
+    /// Operations reverting the session, the last applied one first
+    pub fn undo_ops(&self) -> Vec<FsOp> {
+        self.records
+            .values()
+            .rev()
+            .filter(|record| record.ok && !record.reverted)
+            .flat_map(|record| record.undo.iter().cloned())
+            .collect()
+    }
+
+    pub fn mark_undone(&self) -> io::Result<()> {
+        mark_undone(&self.dir)
+    }
+
+    // cut short, as opposed to still being applied by another process,
+    // which keeps the journal locked for as long as it runs
+    fn is_interrupted(&self) -> bool {
+        self.status == Status::Running
+            && !self.plan.is_empty()
+            && File::open(self.dir.join(JOURNAL)).is_ok_and(|file| file.try_lock().is_ok())
+    }
+}
+
+/// The sessions applied to the root directory, the last one first
+fn sessions(state_dir: &Path, root: &Path) -> io::Result<Vec<Session>> {
+    let root = fs::canonicalize(root)?;
+    let mut sessions = Vec::new();
+    for dir in session_dirs(&state_dir.join("sessions"))?.into_iter().rev() {
+        match Session::load(dir) {
+            Ok(session) if session.root == root => sessions.push(session),
+            Ok(_) => (),
+            // a session being created or removed concurrently
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(sessions)
+}
+
+/// The last session applied to the root directory that was not undone yet,
+/// sessions reverting other ones are not considered
+pub fn last_session(state_dir: &Path, root: &Path) -> crate::Result<Option<Session>> {
+    for session in sessions(state_dir, root)? {
+        if session.is_interrupted() {
+            return Err(TreeEditError::UnfinishedSession(session.root));
+        }
+        if !session.undo_session && matches!(session.status, Status::Done | Status::Failed) {
+            return Ok(Some(session));
+        }
+    }
+    Ok(None)
+}
+
+/// A session that was cut short before all of its operations ran
+pub struct Unfinished {
+    journal: Journal,
+    done: Vec<(usize, Vec<FsOp>)>,
+    // ran, but was cut short before that could be recorded
+    in_doubt: Option<usize>,
+    start: usize,
+    /// Operations that did not run yet
+    pub pending: Vec<FsOp>,
+    /// It was cut short while rolling back, which can only be carried on
+    pub rolling_back: bool,
+}
+
+/// The last session applied to the root directory, if it was cut short
+pub fn unfinished_session(state_dir: &Path, root: &Path) -> io::Result<Option<Unfinished>> {
+    let Some(session) = sessions(state_dir, root)?
+        .into_iter()
+        .find(|session| session.status != Status::Running || session.is_interrupted())
+    else {
+        return Ok(None);
+    };
+    if !session.is_interrupted() {
+        return Ok(None);
+    }
+    // reverting already started, what ran before has partly been undone
+    let rolling_back = session.records.values().any(|record| record.reverted);
+    let mut done = session
+        .records
+        .iter()
+        .filter(|(_, record)| record.ok && !record.reverted)
+        .map(|(&i, record)| (i, record.undo.clone()))
+        .collect::<Vec<_>>();
+    // only the operation running at the time can be unrecorded, it ran
+    // if the file system no longer looks the way it expects
+    let in_doubt = match session.records.last_key_value() {
+        Some((&i, record))
+            if !rolling_back && !record.ok && fsop::check(&session.plan[i]).is_err() =>
+        {
+            done.push((i, record.undo.clone()));
+            Some(i)
+        }
+        _ => None,
+    };
+    let start = done.last().map_or(0, |(i, _)| i + 1);
+    let backup_count = match fs::read_dir(session.dir.join("backup")) {
+        Ok(read_dir) => read_dir.count(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e),
+    };
+    let file = fs::OpenOptions::new()
+        .append(true)
+        .open(session.dir.join(JOURNAL))?;
+    file.try_lock()?;
+    let journal = Journal {
+        file,
+        dir: session.dir,
+        backup_count,
+        undoes: None,
+        git: None,
+    };
+    Ok(Some(Unfinished {
+        journal,
+        done,
+        in_doubt,
+        start,
+        pending: session.plan.into_iter().skip(start).collect(),
+        rolling_back,
+    }))
+}
+
+impl Unfinished {
+    /// Operations reverting what already ran, the last applied one first
+    pub fn undo_ops(&self) -> Vec<FsOp> {
+        self.done
+            .iter()
+            .rev()
+            .flat_map(|(_, undo)| undo.iter().cloned())
+            .collect()
+    }
+
+    pub fn with_git(mut self, git: Option<Git>) -> Unfinished {
+        self.journal.git = git;
+        self
+    }
+
+    /// Runs the pending operations, rolling everything back if one of them fails
+    pub fn finish(mut self) -> crate::Result<()> {
+        self.confirm_in_doubt()?;
+        self.journal.run(&self.pending, self.start, self.done)
+    }
+
+    pub fn roll_back(mut self) -> crate::Result<()> {
+        self.confirm_in_doubt()?;
+        let errors = self.journal.rollback(self.done)?;
+        if !errors.is_empty() {
+            return Err(TreeEditError::RollbackFailed(errors));
+        }
+        Ok(())
+    }
+
+    fn confirm_in_doubt(&mut self) -> io::Result<()> {
+        match self.in_doubt {
+            Some(i) => self.journal.write(&format!("ok {}\n", i)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn session_dirs(sessions_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut dirs = match fs::read_dir(sessions_dir) {
+        Ok(read_dir) => read_dir
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    dirs.sort();
+    Ok(dirs)
+}
+
+// the oldest sessions go first; one that is still running, was cut short or
+// failed to roll back is kept for resume or undo, whatever its age
+fn prune_sessions(sessions_dir: &Path) -> io::Result<()> {
+    let dirs = session_dirs(sessions_dir)?;
+    let mut excess = dirs.len().saturating_sub(MAX_SESSIONS);
+    for dir in dirs {
+        if excess == 0 {
+            break;
+        }
+        let finished = Session::load(dir.clone())
+            .is_ok_and(|session| matches!(session.status, Status::Done | Status::Undone));
+        if finished {
+            fs::remove_dir_all(dir)?;
+            excess -= 1;
+        }
+    }
+    Ok(())
+}
+
+// the state directory may well be on another file system than the files
+fn move_across_devices(src: &Path, dst: &Path) -> io::Result<()> {
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::rename(src, dst) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            if fs::symlink_metadata(src)?.is_symlink() {
+                symlink(fs::read_link(src)?, dst)?;
+            } else {
+                fs::copy(src, dst)?;
+            }
+            fs::remove_file(src)
+        }
+        result => result,
+    }
+}
+
+// what reverts an operation, computed before it runs
+fn inverse(op: &FsOp) -> Vec<FsOp> {
+    let metadata = |path: &Path| fs::symlink_metadata(path).ok();
+    let (mut undo, created_dir) = match op {
+        FsOp::CreateFile { path } | FsOp::CreateSymlink { path, .. } => (
+            vec![FsOp::RemoveFile {
+                path: path.to_path_buf(),
+            }],
+            path.parent(),
+        ),
+        FsOp::CopyFile { dst, .. } | FsOp::HardLink { dst, .. } => (
+            vec![FsOp::RemoveFile {
+                path: dst.to_path_buf(),
+            }],
+            dst.parent(),
+        ),
+        FsOp::CreateDir { path } => (Vec::new(), Some(path.as_ref())),
+        FsOp::MoveFile { src, dst } => (
+            vec![FsOp::MoveFile {
+                src: dst.to_path_buf(),
+                dst: src.to_path_buf(),
+            }],
+            dst.parent(),
+        ),
+        FsOp::MoveDir { src, dst } => (
+            vec![FsOp::MoveDir {
+                src: dst.to_path_buf(),
+                dst: src.to_path_buf(),
+            }],
+            dst.parent(),
+        ),
+        FsOp::RetargetSymlink { path, .. } => match fs::read_link(path) {
+            Ok(target) => (
+                vec![FsOp::RetargetSymlink {
+                    path: path.to_path_buf(),
+                    target,
+                }],
+                None,
+            ),
+            Err(_) => (Vec::new(), None),
+        },
+        FsOp::SetPermissions { path, .. } => match metadata(path) {
+            Some(m) => (
+                vec![FsOp::SetPermissions {
+                    path: path.to_path_buf(),
+                    mode: m.mode() & 0o7777,
+                }],
+                None,
+            ),
+            None => (Vec::new(), None),
+        },
+        FsOp::Chown { path, .. } => match metadata(path) {
+            Some(m) => (
+                vec![FsOp::Chown {
+                    path: path.to_path_buf(),
+                    user: owner::user_name(m.uid()),
+                    group: owner::group_name(m.gid()),
+                }],
+                None,
+            ),
+            None => (Vec::new(), None),
+        },
+        // a pruned directory that was kept is left as it is
+        FsOp::RemoveDir { path } | FsOp::PruneDir { path } => match metadata(path) {
+            Some(m) => (
+                vec![
+                    FsOp::CreateDir {
+                        path: path.to_path_buf(),
+                    },
+                    FsOp::SetPermissions {
+                        path: path.to_path_buf(),
+                        mode: m.mode() & 0o7777,
+                    },
+                ],
+                None,
+            ),
+            None => (Vec::new(), None),
+        },
+        FsOp::RemoveFile { .. } | FsOp::TrashFile { .. } => {
+            unreachable!("reverted through the journal")
+        }
+    };
+    // directories created along the way are removed again, deepest first
+    let missing = created_dir
+        .into_iter()
+        .flat_map(Path::ancestors)
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .take_while(|dir| metadata(dir).is_none());
+    undo.extend(missing.map(|dir| FsOp::PruneDir {
+        path: dir.to_path_buf(),
+    }));
+    undo
+}
+
+fn format_op(op: &FsOp) -> String {
+    let q = |path: &Path| quote(path.as_os_str());
+    match op {
+        FsOp::CreateFile { path } => format!("create-file {}", q(path)),
+        FsOp::CreateDir { path } => format!("create-dir {}", q(path)),
+        FsOp::CreateSymlink { path, target } => {
+            format!("create-symlink {} {}", q(path), q(target))
+        }
+        FsOp::RetargetSymlink { path, target } => {
+            format!("retarget-symlink {} {}", q(path), q(target))
+        }
+        FsOp::MoveFile { src, dst } => format!("move-file {} {}", q(src), q(dst)),
+        FsOp::MoveDir { src, dst } => format!("move-dir {} {}", q(src), q(dst)),
+        FsOp::CopyFile { src, dst } => format!("copy-file {} {}", q(src), q(dst)),
+        FsOp::HardLink { src, dst } => format!("hard-link {} {}", q(src), q(dst)),
+        FsOp::SetPermissions { path, mode } => format!("chmod {:o} {}", mode, q(path)),
+        FsOp::Chown { path, user, group } => format!(
+            "chown {} {} {}",
+            quote(OsStr::new(user)),
+            quote(OsStr::new(group)),
+            q(path)
+        ),
+        FsOp::RemoveFile { path } => format!("remove-file {}", q(path)),
+        FsOp::TrashFile { path } => format!("trash-file {}", q(path)),
+        FsOp::RemoveDir { path } => format!("remove-dir {}", q(path)),
+        FsOp::PruneDir { path } => format!("prune-dir {}", q(path)),
+    }
+}
+
+fn parse_op(s: &str) -> Option<FsOp> {
+    let (name, mut rest) = s.split_once(' ')?;
+    let mut arg = || {
+        let (arg, r) = unquote(rest.trim_start())?;
+        rest = r;
+        Some(arg)
+    };
+    let mut path = || arg().map(PathBuf::from);
+    let op = match name {
+        "create-file" => FsOp::CreateFile { path: path()? },
+        "create-dir" => FsOp::CreateDir { path: path()? },
+        "create-symlink" => FsOp::CreateSymlink {
+            path: path()?,
+            target: path()?,
+        },
+        "retarget-symlink" => FsOp::RetargetSymlink {
+            path: path()?,
+            target: path()?,
+        },
+        "move-file" => FsOp::MoveFile {
+            src: path()?,
+            dst: path()?,
+        },
+        "move-dir" => FsOp::MoveDir {
+            src: path()?,
+            dst: path()?,
+        },
+        "copy-file" => FsOp::CopyFile {
+            src: path()?,
+            dst: path()?,
+        },
+        "hard-link" => FsOp::HardLink {
+            src: path()?,
+            dst: path()?,
+        },
+        "remove-file" => FsOp::RemoveFile { path: path()? },
+        "trash-file" => FsOp::TrashFile { path: path()? },
+        "remove-dir" => FsOp::RemoveDir { path: path()? },
+        "prune-dir" => FsOp::PruneDir { path: path()? },
+        "chmod" => {
+            let (mode, path) = rest.split_once(' ')?;
+            FsOp::SetPermissions {
+                path: PathBuf::from(unquote(path)?.0),
+                mode: u32::from_str_radix(mode, 8).ok()?,
+            }
+        }
+        "chown" => {
+            let mut name = || arg()?.into_string().ok();
+            FsOp::Chown {
+                user: name()?,
+                group: name()?,
+                path: PathBuf::from(arg()?),
+            }
+        }
+        _ => return None,
+    };
+    Some(op)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DetectedBy;
+    use crate::fsutils::tmpfile::TmpDir;
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+
+    #[test]
+    fn test_format_and_parse_ops() {
+        let odd = PathBuf::from(OsString::from_vec(b"dir/\"odd\" \xff name".to_vec()));
+        let ops = [
+            FsOp::CreateSymlink {
+                path: PathBuf::from("link"),
+                target: odd.clone(),
+            },
+            FsOp::MoveFile {
+                src: odd.clone(),
+                dst: PathBuf::from("plain"),
+            },
+            FsOp::SetPermissions {
+                path: odd.clone(),
+                mode: 0o4755,
+            },
+            FsOp::Chown {
+                path: odd.clone(),
+                user: "nobody".to_string(),
+                group: "no group".to_string(),
+            },
+            FsOp::PruneDir {
+                path: PathBuf::from("dir"),
+            },
+        ];
+        for op in &ops {
+            let line = format_op(op);
+            let parsed = parse_op(&line).unwrap();
+            assert_eq!(format!("{:?}", parsed), format!("{:?}", op), "{}", line);
+        }
+        assert!(parse_op("move-file \"only one\"").is_none());
+    }
+
+    #[test]
+    fn test_undo_session() -> crate::Result<()> {
+        let tmp = TmpDir::new("tree-edit-journal")?;
+        let dir = tmp.path();
+        let state_dir = dir.join("state");
+        let root = dir.join("root");
+        fs::create_dir_all(&root)?;
+        let path = |name: &str| root.join(name);
+        fs::write(root.join("a"), "a")?;
+        fs::write(root.join("b"), "b")?;
+        let ops = [
+            FsOp::MoveFile {
+                src: path("a"),
+                dst: path("new/dir/a"),
+            },
+            FsOp::RemoveFile { path: path("b") },
+            FsOp::CreateFile { path: path("c") },
+        ];
+        Journal::create(&state_dir, &root, None)?.exec_all(&ops)?;
+        assert!(root.join("new/dir/a").exists());
+        assert!(!root.join("b").exists());
+
+        let session = last_session(&state_dir, &root)?.unwrap();
+        Journal::create(&state_dir, &root, Some(&session))?.exec_all(&session.undo_ops())?;
+        assert_eq!(fs::read_to_string(root.join("a"))?, "a");
+        assert_eq!(fs::read_to_string(root.join("b"))?, "b");
+        assert!(!root.join("c").exists());
+        assert!(!root.join("new").exists());
+        // neither the reverted session nor the one reverting it can be undone
+        assert!(last_session(&state_dir, &root)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollback() -> crate::Result<()> {
+        let tmp = TmpDir::new("tree-edit-rollback")?;
+        let dir = tmp.path();
+        let state_dir = dir.join("state");
+        let root = dir.join("root");
+        fs::create_dir_all(&root)?;
+        let path = |name: &str| root.join(name);
+        fs::write(root.join("a"), "a")?;
+        fs::write(root.join("b"), "b")?;
+        let ops = [
+            FsOp::MoveFile {
+                src: path("a"),
+                dst: path("b.backup"),
+            },
+            FsOp::MoveFile {
+                src: path("b"),
+                dst: path("a"),
+            },
+            FsOp::RemoveFile { path: path("a") },
+            FsOp::CreateFile {
+                path: path("new/c"),
+            },
+            FsOp::MoveFile {
+                src: path("b.backup"),
+                dst: path("b"),
+            },
+            FsOp::MoveFile {
+                src: path("missing"),
+                dst: path("x"),
+            },
+        ];
+        let error = Journal::create(&state_dir, &root, None)?
+            .exec_all(&ops)
+            .unwrap_err();
+        match error {
+            TreeEditError::ApplyFailed(error, rollback_errors) => {
+                assert!(matches!(
+                    *error,
+                    TreeEditError::FsChanged(DetectedBy::FileNotFound(_))
+                ));
+                assert!(rollback_errors.is_empty());
+            }
+            error => panic!("unexpected error {}", error),
+        }
+        assert_eq!(fs::read_to_string(root.join("a"))?, "a");
+        assert_eq!(fs::read_to_string(root.join("b"))?, "b");
+        assert_eq!(fs::read_dir(&root)?.count(), 2);
+        // a rolled back session leaves nothing to undo
+        assert!(last_session(&state_dir, &root)?.is_none());
+        Ok(())
+    }
+
+    // a state directory on another file system than the given one, when there
+    // is one at hand, so that backups cannot simply be renamed back
+    fn other_device_dir(dir: &Path) -> io::Result<TmpDir> {
+        let dev = fs::metadata(dir)?.dev();
+        let shm = Path::new("/dev/shm");
+        match fs::metadata(shm) {
+            Ok(metadata) if metadata.is_dir() && metadata.dev() != dev => {
+                TmpDir::new_in(shm, "tree-edit-state")
+            }
+            _ => TmpDir::new("tree-edit-state"),
+        }
+    }
+
+    #[test]
+    fn test_undo_permanent_across_devices() -> crate::Result<()> {
+        let tmp = TmpDir::new("tree-edit-backup")?;
+        let root = tmp.path();
+        let state_tmp = other_device_dir(root)?;
+        let state_dir = state_tmp.path();
+        fs::write(root.join("a"), "a")?;
+        fs::create_dir(root.join("d"))?;
+        symlink("a", root.join("d/link"))?;
+        let ops = [
+            FsOp::RemoveFile {
+                path: root.join("a"),
+            },
+            FsOp::RemoveFile {
+                path: root.join("d/link"),
+            },
+            FsOp::RemoveDir {
+                path: root.join("d"),
+            },
+        ];
+        Journal::create(state_dir, root, None)?.exec_all(&ops)?;
+        assert_eq!(fs::read_dir(root)?.count(), 0);
+
+        let session = last_session(state_dir, root)?.unwrap();
+        Journal::create(state_dir, root, Some(&session))?.exec_all(&session.undo_ops())?;
+        assert_eq!(fs::read_to_string(root.join("a"))?, "a");
+        assert_eq!(fs::read_link(root.join("d/link"))?, Path::new("a"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollback_permanent_across_devices() -> crate::Result<()> {
+        let tmp = TmpDir::new("tree-edit-backup-rollback")?;
+        let root = tmp.path();
+        let state_tmp = other_device_dir(root)?;
+        let state_dir = state_tmp.path();
+        let path = |name: &str| root.join(name);
+        fs::write(root.join("a"), "a")?;
+        fs::write(root.join("b"), "b")?;
+        let ops = [
+            FsOp::RemoveFile { path: path("a") },
+            FsOp::MoveFile {
+                src: path("missing"),
+                dst: path("x"),
+            },
+        ];
+        let error = Journal::create(state_dir, root, None)?
+            .exec_all(&ops)
+            .unwrap_err();
+        assert!(
+            matches!(&error, TreeEditError::ApplyFailed(_, errors) if errors.is_empty()),
+            "{}",
+            error
+        );
+        assert_eq!(fs::read_to_string(root.join("a"))?, "a");
+
+        // and when rolling back what was cut short
+        let ops = [
+            FsOp::RemoveFile { path: path("a") },
+            FsOp::RemoveFile { path: path("b") },
+        ];
+        interrupted_session(state_dir, root, &ops, 1)?;
+        unfinished_session(state_dir, root)?.unwrap().roll_back()?;
+        assert_eq!(fs::read_to_string(root.join("a"))?, "a");
+        assert_eq!(fs::read_to_string(root.join("b"))?, "b");
+        assert_eq!(fs::read_dir(root)?.count(), 2);
+        Ok(())
+    }
+
+    // a session that went as far as running `ran` operations and the one after,
+    // without recording the latter, then was cut short
+    fn interrupted_session(
+        state_dir: &Path,
+        root: &Path,
+        ops: &[FsOp],
+        ran: usize,
+    ) -> crate::Result<()> {
+        let mut journal = Journal::create(state_dir, root, None)?;
+        let plan = ops
+            .iter()
+            .map(|op| format!("plan {}\n", format_op(op)))
+            .collect::<String>();
+        journal.write(&plan)?;
+        for (i, op) in ops[..ran].iter().enumerate() {
+            journal.exec(i, op)?;
+        }
+        let (undo, action) = journal.prepare(&ops[ran])?;
+        let mut record = format!("op {} {}\n", ran, format_op(&ops[ran]));
+        for op in &undo {
+            record.push_str(&format!("undo {}\n", format_op(op)));
+        }
+        journal.write(&record)?;
+        journal.perform(&ops[ran], action)?;
+        // dropping the journal unlocks it, as if the process was gone
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume() -> crate::Result<()> {
+        let tmp = TmpDir::new("tree-edit-resume")?;
+        let dir = tmp.path();
+        let state_dir = dir.join("state");
+        let root = dir.join("root");
+        fs::create_dir_all(&root)?;
+        let path = |name: &str| root.join(name);
+        let ops = [
+            FsOp::MoveFile {
+                src: path("a"),
+                dst: path("b"),
+            },
+            FsOp::RemoveFile { path: path("c") },
+            FsOp::CreateFile { path: path("d") },
+        ];
+
+        for roll_back in [false, true] {
+            fs::write(root.join("a"), "a")?;
+            fs::write(root.join("c"), "c")?;
+            interrupted_session(&state_dir, &root, &ops, 1)?;
+            assert!(matches!(
+                last_session(&state_dir, &root),
+                Err(TreeEditError::UnfinishedSession(_))
+            ));
+            let unfinished = unfinished_session(&state_dir, &root)?.unwrap();
+            // removing c ran but was never recorded
+            assert_eq!(unfinished.in_doubt, Some(1));
+            assert_eq!(unfinished.pending.len(), 1);
+            if roll_back {
+                unfinished.roll_back()?;
+                assert_eq!(fs::read_to_string(root.join("a"))?, "a");
+                assert_eq!(fs::read_to_string(root.join("c"))?, "c");
+                assert_eq!(fs::read_dir(&root)?.count(), 2);
+                assert!(last_session(&state_dir, &root)?.is_none());
+            } else {
+                unfinished.finish()?;
+                assert_eq!(fs::read_to_string(root.join("b"))?, "a");
+                assert!(root.join("d").exists());
+                assert_eq!(fs::read_dir(&root)?.count(), 2);
+                let session = last_session(&state_dir, &root)?.unwrap();
+                assert_eq!(session.undo_ops().len(), 3);
+                Journal::create(&state_dir, &root, Some(&session))?
+                    .exec_all(&session.undo_ops())?;
+            }
+            assert!(unfinished_session(&state_dir, &root)?.is_none());
+            for entry in fs::read_dir(&root)? {
+                fs::remove_file(entry?.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume_rollback() -> crate::Result<()> {
+        let tmp = TmpDir::new("tree-edit-resume-rollback")?;
+        let dir = tmp.path();
+        let state_dir = dir.join("state");
+        let root = dir.join("root");
+        fs::create_dir_all(&root)?;
+        let path = |name: &str| root.join(name);
+        fs::write(root.join("a"), "a")?;
+        fs::write(root.join("c"), "c")?;
+        let ops = [
+            FsOp::MoveFile {
+                src: path("a"),
+                dst: path("b"),
+            },
+            FsOp::MoveFile {
+                src: path("c"),
+                dst: path("d"),
+            },
+            FsOp::MoveFile {
+                src: path("z"),
+                dst: path("missing"),
+            },
+        ];
+        // the last operation failed, and rolling back was cut short
+        // after reverting the one before
+        let mut journal = Journal::create(&state_dir, &root, None)?;
+        let plan = ops
+            .iter()
+            .map(|op| format!("plan {}\n", format_op(op)))
+            .collect::<String>();
+        journal.write(&plan)?;
+        journal.exec(0, &ops[0])?;
+        let undo = journal.exec(1, &ops[1])?;
+        journal.write(&format!("op 2 {}\n", format_op(&ops[2])))?;
+        for op in &undo {
+            let (_, action) = journal.prepare(op)?;
+            journal.perform(op, action)?;
+        }
+        journal.write("reverted 1\n")?;
+        drop(journal);
+
+        let unfinished = unfinished_session(&state_dir, &root)?.unwrap();
+        assert!(unfinished.rolling_back);
+        assert_eq!(unfinished.in_doubt, None);
+        // moving c back already ran, and z was never moved
+        let undo_ops = unfinished.undo_ops();
+        assert!(matches!(&undo_ops[..], [FsOp::MoveFile { src, .. }] if src == &path("b")));
+        unfinished.roll_back()?;
+        assert_eq!(fs::read_to_string(root.join("a"))?, "a");
+        assert_eq!(fs::read_to_string(root.join("c"))?, "c");
+        assert_eq!(fs::read_dir(&root)?.count(), 2);
+        assert!(unfinished_session(&state_dir, &root)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_sessions_keeps_unfinished() -> crate::Result<()> {
+        let tmp = TmpDir::new("tree-edit-prune-sessions")?;
+        let state_dir = tmp.path().join("state");
+        let other_root = tmp.path().join("other");
+        let root = tmp.path().join("root");
+        fs::create_dir_all(&other_root)?;
+        fs::create_dir_all(&root)?;
+        fs::write(other_root.join("a"), "a")?;
+        let ops = [FsOp::RemoveFile {
+            path: other_root.join("a"),
+        }];
+        interrupted_session(&state_dir, &other_root, &ops, 0)?;
+        for _ in 0..MAX_SESSIONS + 1 {
+            Journal::create(&state_dir, &root, None)?.exec_all(&[])?;
+        }
+        let sessions_dir = state_dir.join("sessions");
+        assert_eq!(session_dirs(&sessions_dir)?.len(), MAX_SESSIONS);
+        // the oldest session of all, but resume still needs it
+        let unfinished = unfinished_session(&state_dir, &other_root)?.unwrap();
+        unfinished.roll_back()?;
+        assert_eq!(fs::read_to_string(other_root.join("a"))?, "a");
+        Ok(())
+    }
+}
@@ -1,77 +1,589 @@
 pub mod entry;
 mod error;
 pub mod fsutils;
+mod journal;
 mod ui;
 
 use std::{
-    borrow::Cow,
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ffi::OsString,
+    fs, io,
+    ops::Bound,
+    os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt},
     panic,
-    path::PathBuf,
+    path::{Component, Path, PathBuf},
 };
 
-use entry::Entry;
+use entry::{Entry, EntryKind, Info, Owner};
 use error::TreeEditError;
-use fsutils::fsop::FsOp;
+pub use fsutils::git::GitMode;
+use fsutils::{
+    fingerprint,
+    fsop::{FsOp, ListedFile, Plan, Preflight},
+    git::Git,
+    owner,
+};
+use journal::Journal;
+pub use ui::{BufferFormat, Column, Output};
 
 pub type Result<T> = std::result::Result<T, TreeEditError>;
 
-pub fn tree_edit(paths: &Vec<PathBuf>) -> Result<()> {
-    let paths: Vec<String> = paths
-        .iter()
-        .map(|p| String::from(p.to_string_lossy()))
-        .collect();
-    let entries: Vec<entry::Entry> = paths
-        .into_iter()
-        .enumerate()
-        .map(|tuple| entry::Entry::new(Some(tuple.0 as u64), tuple.1))
-        .collect();
-    let new_entries = ui::user_edit_entries(&entries)?;
-    let ops = diff(&entries, &new_entries)?;
+#[derive(Default)]
+pub struct Options {
+    /// Remove directories left empty after applying the changes
+    pub prune_empty_dirs: bool,
+    /// How entries are laid out in the edit buffer
+    pub format: BufferFormat,
+    /// Show and edit permission bits
+    pub mode_column: bool,
+    /// Show and edit the owning user and group
+    pub owner_column: bool,
+    /// Read-only metadata shown after each entry
+    pub columns: Vec<Column>,
+    /// Directory the paths are relative to, shown in the buffer header
+    pub root: PathBuf,
+    /// Descriptions of what was left out of the listing, shown in the buffer header
+    pub filters: Vec<String>,
+    /// Accept absolute paths and paths leading out of the root directory
+    pub allow_outside_root: bool,
+    /// Delete files instead of moving them to the trash
+    pub permanent: bool,
+    /// Check the operations against the file system instead of applying them
+    pub dry_run: bool,
+    /// How the operations are shown before applying them
+    pub output: Output,
+    /// Read the edited listing from this file instead of opening an editor, `-` for stdin
+    pub edit_script: Option<PathBuf>,
+    /// Apply without asking for confirmation
+    pub yes: bool,
+    /// Whether moves and removals of tracked files update the git index
+    pub git: GitMode,
+    /// Also fingerprint the content of listed files, not only their metadata
+    pub hash_contents: bool,
+}
+
+pub fn tree_edit(paths: &[PathBuf], options: &Options) -> Result<()> {
+    let state_dir = journal::state_dir()?;
+    // editing on top of a half applied plan would only make things worse
+    if journal::unfinished_session(&state_dir, &options.root)?.is_some() {
+        return Err(TreeEditError::UnfinishedSession(options.root.clone()));
+    }
+    let git = git(options)?;
+    let git_status = git.as_ref().map(Git::status).transpose()?;
+    let mut entries = read_entries(paths, options, git_status.as_ref())?;
+    let mut new_entries = match options.edit_script {
+        Some(ref script) => ui::read_edit_script(script, &entries, options)?,
+        None => ui::user_edit_entries(&entries, options)?,
+    };
+    // cosmetic differences in how a path is written never become operations
+    normalize_entries(&mut entries);
+    normalize_entries(&mut new_entries);
+    let mut ops = diff_entries(&entries, &new_entries, options.allow_outside_root)?;
+    if !options.permanent {
+        for op in ops.iter_mut() {
+            if let FsOp::RemoveFile { path } = op {
+                *op = FsOp::TrashFile { path: path.clone() };
+            }
+        }
+    }
     panic::catch_unwind(|| verify(&entries, &new_entries, &ops)).expect(concat!(
         "internal verification failed, ",
         "this is likely due to a bug in the implementation, ",
         "please submit an issue here: ",
         "https://github.com/vhminh/tree-edit/issues/new"
     ));
-    ui::display_ops(&ops);
+    // cleanup phase, runs after everything else
+    if options.prune_empty_dirs && !ops.is_empty() {
+        ops.append(&mut prune_ops(&entries, &new_entries));
+    }
+    let listed = listed_files(&entries, &ops);
+    run_plan(&Plan { ops, listed }, options, &state_dir, git)
+}
+
+/// Prints the listing that would be opened in the editor, for another
+/// program to transform and pass back with `--edit-script`
+pub fn print_listing(paths: &[PathBuf], options: &Options) -> Result<()> {
+    let git_status = git(options)?.as_ref().map(Git::status).transpose()?;
+    let entries = read_entries(paths, options, git_status.as_ref())?;
+    ui::print_listing(&entries, options)?;
+    Ok(())
+}
+
+/// Applies a plan exported with `--output json`, once it is checked
+/// against the file system as it is now and the files as they were listed
+pub fn apply_plan(plan_path: &Path, options: &Options) -> Result<()> {
+    let state_dir = journal::state_dir()?;
+    if journal::unfinished_session(&state_dir, &options.root)?.is_some() {
+        return Err(TreeEditError::UnfinishedSession(options.root.clone()));
+    }
+    let plan: Plan = serde_json::from_reader(io::BufReader::new(fs::File::open(plan_path)?))
+        .map_err(TreeEditError::InvalidPlan)?;
+    // a plan may come from anywhere, it is held to the same rules as an edited buffer
+    for path in plan.ops.iter().flat_map(FsOp::paths) {
+        if !options.allow_outside_root && is_outside_root(&normalize(path)) {
+            return Err(TreeEditError::PathOutsideRoot(path.to_path_buf()));
+        }
+    }
+    run_plan(&plan, options, &state_dir, git(options)?)
+}
+
+fn run_plan(plan: &Plan, options: &Options, state_dir: &Path, git: Option<Git>) -> Result<()> {
+    match options.output {
+        Output::Text => ui::display_ops(&plan.ops),
+        // the plan is applied later, with `tree-edit apply`
+        Output::Json => {
+            ui::print_plan(plan)?;
+            return Ok(());
+        }
+        // the script does the applying
+        Output::Sh => {
+            ui::print_script(&plan.ops)?;
+            return Ok(());
+        }
+    }
+    let ops = &plan.ops;
     if ops.is_empty() {
         eprintln!("nothing to do")
-    } else if ui::user_confirm()? {
-        fsutils::fsop::exec_all(&ops)?;
-        eprintln!("successfully applied {} operation(s)", ops.len())
+    } else if options.dry_run {
+        check_fingerprints(plan)?;
+        preflight(ops)?;
+        eprintln!("dry run, {} operation(s) would be applied", ops.len())
+    } else {
+        preflight(ops)?;
+        if confirm(options)? && confirm_uncommitted(ops, git.as_ref(), options)? {
+            // the listed files may have been changed while the editor was open,
+            // or since the plan was saved
+            check_fingerprints(plan)?;
+            Journal::create(state_dir, &options.root, None)?
+                .with_git(git)
+                .exec_all(ops)?;
+            eprintln!("successfully applied {} operation(s)", ops.len())
+        }
+    }
+    Ok(())
+}
+
+/// Reverts the last changes applied to the root directory
+pub fn undo(options: &Options) -> Result<()> {
+    let state_dir = journal::state_dir()?;
+    let Some(session) = journal::last_session(&state_dir, &options.root)? else {
+        eprintln!("nothing to undo");
+        return Ok(());
+    };
+    let ops = session.undo_ops();
+    ui::display_ops(&ops);
+    if ops.is_empty() {
+        eprintln!("nothing to do");
+        session.mark_undone()?;
+    } else if confirm(options)? {
+        Journal::create(&state_dir, &options.root, Some(&session))?
+            .with_git(git(options)?)
+            .exec_all(&ops)?;
+        eprintln!("successfully reverted {} operation(s)", ops.len())
+    }
+    Ok(())
+}
+
+/// Finishes the changes to the root directory that were cut short,
+/// or rolls back the part that was applied
+pub fn resume(options: &Options, roll_back: bool) -> Result<()> {
+    let state_dir = journal::state_dir()?;
+    let Some(unfinished) = journal::unfinished_session(&state_dir, &options.root)? else {
+        eprintln!("nothing to resume");
+        return Ok(());
+    };
+    let unfinished = unfinished.with_git(git(options)?);
+    if roll_back {
+        let ops = unfinished.undo_ops();
+        ui::display_ops(&ops);
+        if confirm(options)? {
+            unfinished.roll_back()?;
+            eprintln!("successfully rolled back {} operation(s)", ops.len())
+        }
+        return Ok(());
+    }
+    if unfinished.rolling_back {
+        return Err(TreeEditError::RollbackCutShort(options.root.clone()));
+    }
+    // the file system may have changed since, make sure the rest still applies
+    if let Err(error) = preflight(&unfinished.pending) {
+        eprintln!("cannot finish, use --rollback to revert what was applied instead");
+        return Err(error);
+    }
+    ui::display_ops(&unfinished.pending);
+    if confirm(options)? {
+        let count = unfinished.pending.len();
+        unfinished.finish()?;
+        eprintln!("successfully applied {} operation(s)", count)
+    }
+    Ok(())
+}
+
+// deleting changes that git has no copy of deserves a second confirmation
+fn confirm_uncommitted(ops: &[FsOp], git: Option<&Git>, options: &Options) -> Result<bool> {
+    let Some(git) = git.filter(|_| !options.yes) else {
+        return Ok(true);
+    };
+    let uncommitted = uncommitted_removals(ops, &git.status()?);
+    if uncommitted.is_empty() {
+        return Ok(true);
+    }
+    eprintln!("these files are untracked or have uncommitted changes:");
+    for path in uncommitted {
+        eprintln!("  {}", path.display());
+    }
+    Ok(ui::user_confirm()?)
+}
+
+// the files deleted by the operations that are untracked or changed,
+// by their `git status` marker
+fn uncommitted_removals<'a>(ops: &'a [FsOp], status: &HashMap<PathBuf, String>) -> Vec<&'a Path> {
+    ops.iter()
+        .filter_map(|op| match op {
+            FsOp::RemoveFile { path } | FsOp::TrashFile { path } => Some(path.as_path()),
+            _ => None,
+        })
+        .filter(|path| {
+            status
+                .get(&normalize(path))
+                .is_some_and(|marker| fsutils::git::is_uncommitted(marker))
+        })
+        .collect()
+}
+
+fn git(options: &Options) -> Result<Option<Git>> {
+    match options.git {
+        GitMode::Auto => Ok(Git::discover(&options.root)),
+        GitMode::Always => match Git::discover(&options.root) {
+            Some(git) => Ok(Some(git)),
+            None => Err(TreeEditError::NotInGitWorkTree(options.root.clone())),
+        },
+        GitMode::Never => Ok(None),
+    }
+}
+
+fn confirm(options: &Options) -> io::Result<bool> {
+    confirm_with(options, ui::user_confirm)
+}
+
+// with --yes the user is never asked
+fn confirm_with(options: &Options, ask: impl FnOnce() -> io::Result<bool>) -> io::Result<bool> {
+    Ok(options.yes || ask()?)
+}
+
+// checks that the listed files the operations read or change are still the
+// ones that were listed, `preflight` only notices files coming and going
+fn check_fingerprints(plan: &Plan) -> Result<()> {
+    for file in &plan.listed {
+        fingerprint::check(&file.path, &file.fingerprint)?;
+    }
+    Ok(())
+}
+
+// the fingerprints of the listed files the operations take from
+fn listed_files(entries: &[Entry], ops: &[FsOp]) -> Vec<ListedFile> {
+    let fingerprints = entries
+        .iter()
+        .filter_map(|e| Some((e.path.as_path(), e.fingerprint.as_ref()?)))
+        .collect::<HashMap<&Path, &entry::Fingerprint>>();
+    listed_sources(ops)
+        .into_iter()
+        .filter_map(|path| {
+            Some(ListedFile {
+                path: path.to_path_buf(),
+                fingerprint: fingerprints.get(path).copied()?.clone(),
+            })
+        })
+        .collect()
+}
+
+// paths the operations take files from, as long as they still point
+// to what was listed, that is before an earlier operation changed them
+fn listed_sources(ops: &[FsOp]) -> Vec<&Path> {
+    let mut changed: Vec<&Path> = Vec::new();
+    let mut sources = Vec::new();
+    for op in ops {
+        let (source, targets) = match op {
+            FsOp::CreateFile { path } | FsOp::CreateDir { path } => (None, vec![path]),
+            FsOp::CreateSymlink { path, .. } => (None, vec![path]),
+            FsOp::RetargetSymlink { path, .. } => (Some(path), vec![path]),
+            FsOp::MoveFile { src, dst } | FsOp::MoveDir { src, dst } => (Some(src), vec![src, dst]),
+            FsOp::CopyFile { src, dst } | FsOp::HardLink { src, dst } => (Some(src), vec![dst]),
+            FsOp::SetPermissions { path, .. } | FsOp::Chown { path, .. } => (Some(path), vec![]),
+            FsOp::RemoveFile { path } | FsOp::TrashFile { path } | FsOp::RemoveDir { path } => {
+                (Some(path), vec![path])
+            }
+            // only removed when nothing is left in it
+            FsOp::PruneDir { .. } => (None, vec![]),
+        };
+        if let Some(source) = source {
+            if !changed.iter().any(|path| source.starts_with(path)) {
+                sources.push(source.as_path());
+            }
+        }
+        changed.extend(targets.into_iter().map(PathBuf::as_path));
+    }
+    sources
+}
+
+// checks the operations against the file system without applying any of them
+fn preflight(ops: &[FsOp]) -> Result<()> {
+    let mut preflight = Preflight::default();
+    for op in ops {
+        preflight.check(op)?;
     }
     Ok(())
 }
 
-pub fn diff<'a: 'b, 'b>(
-    old_entries: &'a Vec<Entry>,
-    new_entries: &'a Vec<Entry>,
-) -> Result<Vec<FsOp<'b>>> {
-    validate_old_entries(&old_entries);
-    let allowed_ids = old_entries
+fn read_entries(
+    paths: &[PathBuf],
+    options: &Options,
+    git_status: Option<&HashMap<PathBuf, String>>,
+) -> Result<Vec<Entry>> {
+    let mut next_id = 0;
+    let mut entries = Vec::new();
+    for path in paths {
+        let metadata = fs::symlink_metadata(path)?;
+        let file_type = metadata.file_type();
+        let mut entry = if file_type.is_dir() {
+            Entry::new_dir(path.clone())
+        } else if file_type.is_symlink() {
+            Entry::new_symlink(Some(next_id), path.clone(), fs::read_link(path)?)
+        } else {
+            Entry::new(Some(next_id), path.clone())
+        };
+        if entry.id.is_some() {
+            next_id += 1;
+        }
+        // the permissions of a symlink are those of its target
+        if options.mode_column && !file_type.is_symlink() {
+            entry.mode = Some(metadata.permissions().mode() & 0o7777);
+        }
+        if options.owner_column {
+            entry.owner = Some(Owner {
+                user: owner::user_name(metadata.uid()),
+                group: owner::group_name(metadata.gid()),
+            });
+        }
+        entry.fingerprint = Some(fingerprint::take(path, &metadata, options.hash_contents)?);
+        let git_status = git_status.and_then(|status| status.get(&normalize(path)).cloned());
+        if !options.columns.is_empty() || git_status.is_some() {
+            entry.info = Some(Info {
+                size: metadata.len(),
+                mtime: metadata.mtime(),
+                file_type: file_type_name(&file_type),
+                git_status,
+            });
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn file_type_name(file_type: &fs::FileType) -> &'static str {
+    if file_type.is_dir() {
+        "dir"
+    } else if file_type.is_symlink() {
+        "symlink"
+    } else if file_type.is_fifo() {
+        "fifo"
+    } else if file_type.is_socket() {
+        "socket"
+    } else if file_type.is_block_device() {
+        "block"
+    } else if file_type.is_char_device() {
+        "char"
+    } else {
+        "file"
+    }
+}
+
+pub fn diff(old_entries: &[Entry], new_entries: &[Entry]) -> Result<Vec<FsOp>> {
+    diff_entries(old_entries, new_entries, false)
+}
+
+fn diff_entries<'a>(
+    old_entries: &'a [Entry],
+    new_entries: &'a [Entry],
+    allow_outside_root: bool,
+) -> Result<Vec<FsOp>> {
+    validate_old_entries(old_entries);
+    let old_by_id = old_entries
         .iter()
-        .map(|e| e.id.unwrap())
+        .filter_map(|e| Some((e.id?, e)))
+        .collect::<HashMap<u64, &Entry>>();
+    validate_new_entries(new_entries, &old_by_id, allow_outside_root)?;
+    let old_dirs = old_entries
+        .iter()
+        .filter(|e| e.is_dir())
+        .map(|e| e.path.as_path())
+        .collect::<BTreeSet<&Path>>();
+    let new_dirs = target_dirs(new_entries);
+    let existing_names = old_entries
+        .iter()
+        .map(|e| e.path.clone())
+        .chain(new_dirs.iter().cloned())
+        .collect::<HashSet<PathBuf>>();
+    let dir_moves = move_dirs(old_entries, new_entries, &old_dirs, &new_dirs);
+    // everything inside a moved directory is taken care of by the move
+    let is_moved = |path: &Path| dir_moves.iter().any(|(src, _)| path.starts_with(src));
+    let moved_ids = old_entries
+        .iter()
+        .filter(|e| is_moved(&e.path))
+        .filter_map(|e| e.id)
         .collect::<HashSet<u64>>();
-    validate_new_entries(&new_entries, &allowed_ids)?;
-    let copy_rm_move_ops = move_files_around_ops(&old_entries, &new_entries);
+    let mut new_dirs = new_dirs;
+    for (src, dst) in &dir_moves {
+        for dir in old_dirs.iter().filter(|d| d.starts_with(src)) {
+            new_dirs.remove(&moved_path(dir, src, dst));
+        }
+    }
+    let old_dirs = old_dirs
+        .into_iter()
+        .filter(|d| !is_moved(d))
+        .collect::<BTreeSet<&Path>>();
+    let old_files = old_entries
+        .iter()
+        .filter(|e| !e.is_dir() && !is_moved(&e.path))
+        .collect::<Vec<&Entry>>();
+    // hard links point to the first plain line of their id, or the first link
+    // if there is none, which then holds the file itself
+    let mut link_sources = HashMap::<u64, &Path>::new();
+    for entry in new_entries.iter().filter(|e| !e.hard_link) {
+        if let Some(id) = entry.id {
+            link_sources.entry(id).or_insert(&entry.path);
+        }
+    }
+    for entry in new_entries.iter().filter(|e| e.hard_link) {
+        link_sources.entry(entry.id.unwrap()).or_insert(&entry.path);
+    }
+    let is_link = |e: &Entry| e.hard_link && link_sources[&e.id.unwrap()] != e.path;
+    let new_files = new_entries
+        .iter()
+        .filter(|e| !e.is_dir() && !is_link(e))
+        .filter(|e| !e.id.is_some_and(|id| moved_ids.contains(&id)))
+        .collect::<Vec<&Entry>>();
+    let move_dir_ops = dir_moves.iter().map(|(src, dst)| FsOp::MoveDir {
+        src: src.to_path_buf(),
+        dst: dst.to_path_buf(),
+    });
+    let (mut copy_rm_move_ops, mut deferred_ops) =
+        move_files_around_ops(&old_files, &new_files, &old_dirs, existing_names);
+    // once every symlink is in place, point the edited ones to their new target
+    let retarget_ops = new_entries.iter().filter_map(|e| match &e.kind {
+        EntryKind::Symlink { target } if old_by_id[&e.id?].kind != e.kind => {
+            Some(FsOp::RetargetSymlink {
+                path: e.path.to_path_buf(),
+                target: target.to_path_buf(),
+            })
+        }
+        _ => None,
+    });
+    // deepest first, a directory can only be removed once it's empty
+    let remove_dir_ops = old_dirs
+        .iter()
+        .rev()
+        .filter(|p| !new_dirs.contains(**p))
+        .map(|p| FsOp::RemoveDir {
+            path: p.to_path_buf(),
+        });
+    // parents first
+    let create_dir_ops = new_dirs
+        .iter()
+        .filter(|p| !old_dirs.contains(p.as_path()))
+        .map(|p| FsOp::CreateDir { path: p.clone() });
+    let hard_link_ops = new_entries
+        .iter()
+        .filter(|e| is_link(e))
+        .map(|e| FsOp::HardLink {
+            src: link_sources[&e.id.unwrap()].to_path_buf(),
+            dst: e.path.to_path_buf(),
+        });
     let create_ops = create_files_ops(new_entries);
+    // directories moved as a whole are compared against their old path
+    let old_path = |path: &'a Path| -> PathBuf {
+        for (src, dst) in &dir_moves {
+            if path.starts_with(dst) {
+                return moved_path(path, dst, src);
+            }
+        }
+        path.to_path_buf()
+    };
+    let old_by_path = old_entries
+        .iter()
+        .map(|e| (e.path.as_path(), e))
+        .collect::<HashMap<&Path, &Entry>>();
+    // what a new entry was before the edit, if anything
+    let old_entry = |e: &'a Entry| match e.id {
+        Some(id) => Some(old_by_id[&id]),
+        None if e.is_dir() => old_by_path
+            .get(old_path(&e.path).as_path())
+            .copied()
+            .filter(|old| old.is_dir()),
+        None => None,
+    };
+    // resolve every name up front, nothing is applied if one is unknown
+    let mut chown_ops = Vec::new();
+    for e in new_entries {
+        let Some(new_owner) = &e.owner else {
+            continue;
+        };
+        if old_entry(e).is_some_and(|old| old.owner.as_ref() == Some(new_owner)) {
+            continue;
+        }
+        if owner::uid(&new_owner.user).is_none() {
+            return Err(TreeEditError::UnknownUser(new_owner.user.clone()));
+        }
+        if owner::gid(&new_owner.group).is_none() {
+            return Err(TreeEditError::UnknownGroup(new_owner.group.clone()));
+        }
+        chown_ops.push(FsOp::Chown {
+            path: e.path.to_path_buf(),
+            user: new_owner.user.clone(),
+            group: new_owner.group.clone(),
+        });
+    }
+    // last, so that no directory is locked before everything is in place,
+    // and after changing owners, which may clear the setuid and setgid bits
+    let set_permissions_ops = new_entries.iter().filter_map(|e| {
+        let mode = e.mode?;
+        if let EntryKind::Symlink { .. } = e.kind {
+            return None;
+        }
+        let old_mode = old_entry(e).and_then(|old| old.mode);
+        (old_mode != Some(mode)).then(|| FsOp::SetPermissions {
+            path: e.path.to_path_buf(),
+            mode,
+        })
+    });
     let mut ops = Vec::new();
-    ops.append(&mut copy_rm_move_ops.collect());
-    ops.append(&mut create_ops.collect());
+    ops.extend(move_dir_ops);
+    ops.append(&mut copy_rm_move_ops);
+    ops.extend(remove_dir_ops);
+    ops.append(&mut deferred_ops);
+    ops.extend(retarget_ops);
+    ops.extend(create_dir_ops);
+    ops.extend(hard_link_ops);
+    ops.extend(create_ops);
+    ops.append(&mut chown_ops);
+    ops.extend(set_permissions_ops);
     Ok(ops)
 }
 
 // all errors causes by our internal generated entries should panic
-fn validate_old_entries(entries: &Vec<Entry>) {
-    // all must have an id
+fn validate_old_entries(entries: &[Entry]) {
+    // all files must have an id, directories must not
     for entry in entries {
-        assert!(entry.id.is_some(), "entry does not have an id {:?}", entry);
+        assert_eq!(
+            entry.id.is_some(),
+            !entry.is_dir(),
+            "entry has an invalid id {:?}",
+            entry
+        );
     }
     // ids must be unique
     let mut ids = HashSet::<u64>::new();
-    for entry in entries {
-        let id = entry.id.unwrap();
+    for id in entries.iter().filter_map(|e| e.id) {
         assert!(!ids.contains(&id), "duplicate entry id {}", id);
         ids.insert(id);
     }
@@ -79,22 +591,60 @@ fn validate_old_entries(entries: &Vec<Entry>) {
     validate_unique_paths(entries).unwrap();
 }
 
-fn validate_new_entries(entries: &Vec<Entry>, allowed_ids: &HashSet<u64>) -> Result<()> {
+fn validate_new_entries(
+    entries: &[Entry],
+    old_by_id: &HashMap<u64, &Entry>,
+    allow_outside_root: bool,
+) -> Result<()> {
     for entry in entries {
+        if entry.path.as_os_str().is_empty() {
+            return Err(TreeEditError::InvalidEntry(String::new()));
+        }
+        if !allow_outside_root && is_outside_root(&entry.path) {
+            return Err(TreeEditError::PathOutsideRoot(entry.path.clone()));
+        }
+        if entry.hard_link && entry.id.is_none() {
+            return Err(TreeEditError::InvalidEntry(
+                entry.path.display().to_string(),
+            ));
+        }
         if let Some(id) = entry.id {
-            if !allowed_ids.contains(&id) {
+            if entry.is_dir() {
+                return Err(TreeEditError::InvalidEntry(format!(
+                    "{} {}/",
+                    id,
+                    entry.path.display()
+                )));
+            }
+            let Some(old_entry) = old_by_id.get(&id) else {
                 return Err(TreeEditError::InvalidFileId(id));
+            };
+            // a file cannot turn into a symlink or the other way around
+            let is_symlink = |e: &Entry| matches!(e.kind, EntryKind::Symlink { .. });
+            if is_symlink(entry) != is_symlink(old_entry) {
+                return Err(TreeEditError::InvalidEntry(format!(
+                    "{} {}",
+                    id,
+                    entry.path.display()
+                )));
             }
         }
     }
     validate_unique_paths(entries)?;
+    // a file cannot also be the parent directory of another entry
+    let dirs = target_dirs(entries);
+    for entry in entries.iter().filter(|e| !e.is_dir()) {
+        if dirs.contains(&entry.path) {
+            return Err(TreeEditError::DuplicatePath(entry.path.clone()));
+        }
+    }
     Ok(())
 }
 
-fn validate_unique_paths(entries: &Vec<Entry>) -> Result<()> {
-    let mut paths = HashSet::<&str>::new();
+fn validate_unique_paths(entries: &[Entry]) -> Result<()> {
+    let mut paths = HashSet::<&Path>::new();
     for entry in entries {
-        if paths.contains(&entry.path as &str) {
+        if paths.contains(entry.path.as_path()) {
             return Err(TreeEditError::DuplicatePath(entry.path.clone()));
         }
         paths.insert(&entry.path);
@@ -102,15 +652,157 @@ fn validate_unique_paths(entries: &Vec<Entry>) -> Result<()> {
     Ok(())
 }
 
-fn gen_backup_path(path: &str, existing_names: &HashSet<String>) -> String {
+fn normalize_entries(entries: &mut [Entry]) {
+    for entry in entries {
+        entry.path = normalize(&entry.path);
+    }
+}
+
+// resolves `.` and `..` lexically, parsing the path into components
+// already takes care of duplicate and trailing slashes
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir
+                if matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                ) =>
+            {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+// only meaningful for normalized paths, where `..` can only come first
+fn is_outside_root(path: &Path) -> bool {
+    path.has_root() || path.starts_with(Component::ParentDir)
+}
+
+// all proper ancestors of a path inside the root, closest first
+fn ancestors(path: &Path) -> impl Iterator<Item = &Path> {
+    path.ancestors()
+        .skip(1)
+        .take_while(|p| !p.as_os_str().is_empty() && *p != Path::new(".") && !is_outside_root(p))
+}
+
+// the path inside dst that the path inside src ends up at when src is moved to dst
+fn moved_path(path: &Path, src: &Path, dst: &Path) -> PathBuf {
+    match path.strip_prefix(src) {
+        Ok(relative) if relative.as_os_str().is_empty() => dst.to_path_buf(),
+        Ok(relative) => dst.join(relative),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+// directories that exist after editing: the listed ones and the ancestors of every entry
+fn target_dirs(entries: &[Entry]) -> BTreeSet<PathBuf> {
+    let mut dirs = BTreeSet::new();
+    for entry in entries {
+        if entry.is_dir() {
+            dirs.insert(entry.path.clone());
+        }
+        for ancestor in ancestors(&entry.path) {
+            if !dirs.insert(ancestor.to_path_buf()) {
+                break;
+            }
+        }
+    }
+    dirs
+}
+
+// finds the old directories whose content all moved to the same new directory,
+// these can be moved with a single rename instead of one move per file
+fn move_dirs<'a>(
+    old_entries: &'a [Entry],
+    new_entries: &'a [Entry],
+    old_dirs: &BTreeSet<&'a Path>,
+    new_dirs: &BTreeSet<PathBuf>,
+) -> Vec<(&'a Path, &'a Path)> {
+    let old_by_path = old_entries
+        .iter()
+        .map(|e| (e.path.as_path(), e))
+        .collect::<BTreeMap<&Path, &Entry>>();
+    let old_implied_dirs = target_dirs(old_entries);
+    let mut new_id_to_paths = HashMap::<u64, Vec<&Path>>::new();
+    for entry in new_entries {
+        if let Some(id) = entry.id {
+            new_id_to_paths.entry(id).or_default().push(&entry.path);
+        }
+    }
+    // the only new path of an old file, if it was neither copied nor removed
+    let single_new_path = |entry: &Entry| match new_id_to_paths.get(&entry.id.unwrap()) {
+        Some(paths) if paths.len() == 1 => Some(paths[0]),
+        _ => None,
+    };
+    let mut moves = Vec::<(&Path, &Path)>::new();
+    // parents come first, so only the outermost directory of a move is picked
+    for dir in old_dirs {
+        if new_dirs.contains(*dir) || moves.iter().any(|(src, _)| dir.starts_with(src)) {
+            continue;
+        }
+        // paths are ordered by component, everything inside follows the directory
+        let children = old_by_path
+            .range::<Path, _>((Bound::Excluded(*dir), Bound::Unbounded))
+            .take_while(|(path, _)| path.starts_with(dir))
+            .map(|(_, entry)| *entry)
+            .collect::<Vec<&Entry>>();
+        // the new location is inferred from any file inside, an empty directory
+        // is simply removed and created again
+        let Some(first_file) = children.iter().find(|e| !e.is_dir()) else {
+            continue;
+        };
+        let relative_path = first_file.path.strip_prefix(dir).unwrap();
+        let Some(target) = single_new_path(first_file)
+            .filter(|new_path| new_path.ends_with(relative_path))
+            .and_then(|new_path| new_path.ancestors().nth(relative_path.components().count()))
+        else {
+            continue;
+        };
+        let overlaps = |other: &Path| target.starts_with(other) || other.starts_with(target);
+        // a file in the way is only removed later, moving its files one by one
+        // lets them be ordered after the removal
+        let under_file = ancestors(target).any(|a| old_by_path.get(a).is_some_and(|e| !e.is_dir()));
+        if target.as_os_str().is_empty()
+            || target == Path::new(".")
+            || old_by_path.contains_key(target)
+            || old_implied_dirs.contains(target)
+            || under_file
+            || moves.iter().any(|(_, dst)| overlaps(dst))
+        {
+            continue;
+        }
+        let all_moved = children.iter().all(|child| {
+            let moved_child = moved_path(&child.path, dir, target);
+            if child.is_dir() {
+                new_dirs.contains(&moved_child)
+            } else {
+                single_new_path(child).is_some_and(|p| p == moved_child)
+            }
+        });
+        if all_moved {
+            moves.push((dir, target));
+        }
+    }
+    moves
+}
+
+fn gen_backup_path(path: &Path, existing_names: &HashSet<PathBuf>) -> PathBuf {
     // FIXME: this can have exponential runtime
     // if a lot of files has the same name as back up (rarely)
     for i in 0..(existing_names.len() + 4) {
-        let tmp_path = if i == 0 {
-            format!("{path}.backup")
+        let mut tmp_path = OsString::from(path);
+        if i == 0 {
+            tmp_path.push(".backup");
         } else {
-            format!("{path}.backup-{i}")
-        };
+            tmp_path.push(format!(".backup-{i}"));
+        }
+        let tmp_path = PathBuf::from(tmp_path);
         if !existing_names.contains(&tmp_path) {
             return tmp_path;
         }
@@ -118,66 +810,76 @@ fn gen_backup_path(path: &str, existing_names: &HashSet<String>) -> String {
     panic!("unreachable*")
 }
 
-fn move_files_around_ops<'a: 'b, 'b>(
-    old_entries: &'a Vec<Entry>,
-    new_entries: &'a Vec<Entry>,
-) -> impl Iterator<Item = FsOp<'b>> {
-    struct Lookup<'a> {
-        old_id_to_path: HashMap<u64, &'a str>,
-        old_path_to_id: HashMap<&'a str, u64>,
-        new_id_to_paths: HashMap<u64, Vec<&'a str>>,
+// returns the ops that copy, move and remove files, and the ops that must
+// wait until the old directories are removed
+fn move_files_around_ops<'a>(
+    old_entries: &[&'a Entry],
+    new_entries: &[&'a Entry],
+    old_dirs: &BTreeSet<&'a Path>,
+    existing_names: HashSet<PathBuf>,
+) -> (Vec<FsOp>, Vec<FsOp>) {
+    struct Lookup<'a, 'd> {
+        old_id_to_path: HashMap<u64, &'a Path>,
+        old_path_to_id: HashMap<&'a Path, u64>,
+        new_id_to_paths: HashMap<u64, Vec<&'a Path>>,
+        old_dirs: &'d BTreeSet<&'a Path>,
     }
     let lookup = Lookup {
         old_id_to_path: {
-            let mut builder = HashMap::<u64, &str>::new();
+            let mut builder = HashMap::<u64, &Path>::new();
             for entry in old_entries {
                 builder.insert(entry.id.unwrap(), &entry.path);
             }
             builder
         },
         old_path_to_id: {
-            let mut builder = HashMap::<&str, u64>::new();
+            let mut builder = HashMap::<&Path, u64>::new();
             for entry in old_entries {
                 builder.insert(&entry.path, entry.id.unwrap());
             }
             builder
         },
         new_id_to_paths: {
-            let mut builder = HashMap::<u64, Vec<&str>>::new();
+            let mut builder = HashMap::<u64, Vec<&Path>>::new();
             for entry in new_entries {
                 if let Some(id) = entry.id {
-                    let v = builder.entry(id).or_insert(Vec::new());
+                    let v = builder.entry(id).or_default();
                     v.push(&entry.path);
                 }
             }
             builder
         },
+        old_dirs,
     };
-    // FIXME: HashSet of Cow<'_, String> ???
-    let mut existing_names: HashSet<String> = lookup
-        .old_path_to_id
-        .keys()
-        .cloned()
-        .map(String::from)
-        .collect();
-    let mut ops = Vec::<FsOp>::new();
-    let mut locked = HashSet::<u64>::new();
-    let mut dirty = HashMap::<u64, FsOp>::new();
-    let mut processed = HashSet::<u64>::new();
-    fn process<'a>(
-        id: u64,
-        existing_names: &mut HashSet<String>,
-        ops: &mut Vec<FsOp<'a>>,
-        processed: &mut HashSet<u64>,
-        locked: &mut HashSet<u64>,
-        dirty: &mut HashMap<u64, FsOp<'a>>,
-        lookup: &Lookup<'a>,
-    ) {
-        if processed.contains(&id) {
+    struct State {
+        existing_names: HashSet<PathBuf>,
+        ops: Vec<FsOp>,
+        deferred: Vec<FsOp>,
+        processed: HashSet<u64>,
+        locked: HashSet<u64>,
+        dirty: HashMap<u64, Vec<FsOp>>,
+    }
+    let mut state = State {
+        existing_names,
+        ops: Vec::new(),
+        deferred: Vec::new(),
+        processed: HashSet::new(),
+        locked: HashSet::new(),
+        dirty: HashMap::new(),
+    };
+    fn move_or_copy(move_instead_of_copy: bool, src: PathBuf, dst: PathBuf) -> FsOp {
+        if move_instead_of_copy {
+            FsOp::MoveFile { src, dst }
+        } else {
+            FsOp::CopyFile { src, dst }
+        }
+    }
+    fn process(id: u64, state: &mut State, lookup: &Lookup) {
+        if state.processed.contains(&id) {
             return;
         }
         let old_path = lookup.old_id_to_path.get(&id).unwrap();
-        locked.insert(id);
+        state.locked.insert(id);
         // copy to new entries
         let new_paths = Vec::new();
         let new_paths = lookup.new_id_to_paths.get(&id).unwrap_or(&new_paths);
@@ -187,180 +889,310 @@ fn move_files_around_ops<'a: 'b, 'b>(
             // move file if we don't need to keep it at the original location
             // and this is the last file in the list
             let move_instead_of_copy = !keep_old_path && new_path_iter.peek().is_none();
-            if let Some(existing_id_at_new_path) = lookup.old_path_to_id.get(*new_path) {
-                if locked.contains(existing_id_at_new_path) {
+            if lookup.old_dirs.contains(new_path) {
+                // the directory only goes away once everything inside has been moved out,
+                // park the file next to it until then
+                let backup_path = gen_backup_path(new_path, &state.existing_names);
+                assert!(state.existing_names.insert(backup_path.clone()));
+                state.ops.push(move_or_copy(
+                    move_instead_of_copy,
+                    old_path.to_path_buf(),
+                    backup_path.clone(),
+                ));
+                state.deferred.push(FsOp::MoveFile {
+                    src: backup_path,
+                    dst: new_path.to_path_buf(),
+                });
+                continue;
+            }
+            // the file currently at the new path, or at one of its parent directories
+            let blocking_id = std::iter::once(*new_path)
+                .chain(ancestors(new_path))
+                .find_map(|p| lookup.old_path_to_id.get(p));
+            if let Some(blocking_id) = blocking_id {
+                if state.locked.contains(blocking_id) {
                     // cycle detected, push to dirty list
-                    let backup_path = gen_backup_path(old_path, &existing_names);
-                    assert!(existing_names.insert(backup_path.clone()));
-                    if move_instead_of_copy {
-                        ops.push(FsOp::MoveFile {
-                            src: Cow::Borrowed(old_path),
-                            dst: Cow::Owned(backup_path.clone()),
-                        });
-                    } else {
-                        ops.push(FsOp::CopyFile {
-                            src: Cow::Borrowed(old_path),
-                            dst: Cow::Owned(backup_path.clone()),
+                    let backup_path = gen_backup_path(old_path, &state.existing_names);
+                    assert!(state.existing_names.insert(backup_path.clone()));
+                    state.ops.push(move_or_copy(
+                        move_instead_of_copy,
+                        old_path.to_path_buf(),
+                        backup_path.clone(),
+                    ));
+                    state
+                        .dirty
+                        .entry(*blocking_id)
+                        .or_default()
+                        .push(FsOp::MoveFile {
+                            src: backup_path,
+                            dst: new_path.to_path_buf(),
                         });
-                    }
-                    dirty.insert(
-                        *existing_id_at_new_path,
-                        FsOp::MoveFile {
-                            src: Cow::Owned(backup_path),
-                            dst: Cow::Borrowed(new_path),
-                        },
-                    );
                     continue;
                 } else {
-                    process(
-                        *existing_id_at_new_path,
-                        existing_names,
-                        ops,
-                        processed,
-                        locked,
-                        dirty,
-                        lookup,
-                    );
+                    process(*blocking_id, state, lookup);
                 }
             }
-            existing_names.insert(new_path.to_string());
-            if move_instead_of_copy {
-                ops.push(FsOp::MoveFile {
-                    src: Cow::Borrowed(old_path),
-                    dst: Cow::Borrowed(new_path),
-                });
-            } else {
-                ops.push(FsOp::CopyFile {
-                    src: Cow::Borrowed(old_path),
-                    dst: Cow::Borrowed(new_path),
-                });
-            }
+            state.existing_names.insert(new_path.to_path_buf());
+            state.ops.push(move_or_copy(
+                move_instead_of_copy,
+                old_path.to_path_buf(),
+                new_path.to_path_buf(),
+            ));
         }
         if new_paths.is_empty() {
-            ops.push(FsOp::RemoveFile {
-                path: Cow::Borrowed(old_path),
+            state.ops.push(FsOp::RemoveFile {
+                path: old_path.to_path_buf(),
             })
         }
-        locked.remove(&id);
+        state.locked.remove(&id);
         // push remaining ops from dirty list
-        if let Some(op) = dirty.remove(&id) {
-            ops.push(op);
+        if let Some(mut ops) = state.dirty.remove(&id) {
+            state.ops.append(&mut ops);
         }
-        processed.insert(id);
+        state.processed.insert(id);
     }
     for id in lookup.old_id_to_path.keys() {
-        process(
-            *id,
-            &mut existing_names,
-            &mut ops,
-            &mut processed,
-            &mut locked,
-            &mut dirty,
-            &lookup,
-        );
+        process(*id, &mut state, &lookup);
     }
-    assert!(dirty.is_empty());
-    ops.into_iter()
+    assert!(state.dirty.is_empty());
+    (state.ops, state.deferred)
 }
 
-fn create_files_ops<'a: 'b, 'b>(new_entries: &'a Vec<Entry>) -> impl Iterator<Item = FsOp<'b>> {
+fn create_files_ops(new_entries: &[Entry]) -> impl Iterator<Item = FsOp> + '_ {
     new_entries
         .iter()
         .filter(|e| e.id.is_none())
-        .map(|e| FsOp::CreateFile {
-            path: Cow::Borrowed(&e.path),
+        .filter_map(|e| match &e.kind {
+            EntryKind::File => Some(FsOp::CreateFile {
+                path: e.path.to_path_buf(),
+            }),
+            EntryKind::Symlink { target } => Some(FsOp::CreateSymlink {
+                path: e.path.to_path_buf(),
+                target: target.to_path_buf(),
+            }),
+            EntryKind::Dir => None,
         })
 }
 
+// old directories that are left without any content, deepest first,
+// directories that were already empty are never touched
+fn prune_ops(old_entries: &[Entry], new_entries: &[Entry]) -> Vec<FsOp> {
+    let had_content = old_entries
+        .iter()
+        .flat_map(|e| ancestors(&e.path))
+        .collect::<HashSet<&Path>>();
+    let new_dirs = target_dirs(new_entries);
+    let mut new_children = HashMap::<&Path, Vec<&Path>>::new();
+    for path in new_entries
+        .iter()
+        .map(|e| e.path.as_path())
+        .chain(new_dirs.iter().map(PathBuf::as_path))
+    {
+        if let Some(parent) = ancestors(path).next() {
+            new_children.entry(parent).or_default().push(path);
+        }
+    }
+    let old_dirs = old_entries
+        .iter()
+        .filter(|e| e.is_dir())
+        .map(|e| e.path.as_path())
+        .collect::<BTreeSet<&Path>>();
+    let mut pruned = HashSet::<&Path>::new();
+    let mut ops = Vec::new();
+    for dir in old_dirs.into_iter().rev() {
+        if !new_dirs.contains(dir) || !had_content.contains(dir) {
+            continue;
+        }
+        let children = new_children.get(dir).map_or(&[][..], Vec::as_slice);
+        if children.iter().all(|child| pruned.contains(child)) {
+            pruned.insert(dir);
+            ops.push(FsOp::PruneDir {
+                path: dir.to_path_buf(),
+            });
+        }
+    }
+    ops
+}
+
 // verify internally that the operations can transform old entries to new ones
-fn verify(old_entries: &Vec<Entry>, new_entries: &Vec<Entry>, ops: &Vec<FsOp>) {
-    let mut entries_after_apply = apply(&old_entries, &ops);
-    let mut new_entries = new_entries.clone();
+fn verify(old_entries: &[Entry], new_entries: &[Entry], ops: &[FsOp]) {
+    let mut entries_after_apply = apply(old_entries, ops);
+    let mut new_entries = new_entries.to_vec();
+    // hard links are only told apart from copies by the ops
+    for entry in new_entries.iter_mut() {
+        entry.hard_link = false;
+    }
+    // permissions and owners that are not given are left as they are
+    let given = new_entries
+        .iter()
+        .map(|e| (e.path.clone(), (e.mode.is_some(), e.owner.is_some())))
+        .collect::<HashMap<PathBuf, (bool, bool)>>();
+    for entry in entries_after_apply.iter_mut() {
+        // annotations are only shown, never part of the edit
+        entry.info = None;
+        entry.fingerprint = None;
+        if let Some((has_mode, has_owner)) = given.get(&entry.path) {
+            if !has_mode {
+                entry.mode = None;
+            }
+            if !has_owner {
+                entry.owner = None;
+            }
+        }
+    }
+    // parent directories are implicitly created
+    let listed_dirs = new_entries
+        .iter()
+        .filter(|e| e.is_dir())
+        .map(|e| e.path.clone())
+        .collect::<HashSet<PathBuf>>();
+    for dir in target_dirs(&new_entries) {
+        if !listed_dirs.contains(&dir) {
+            new_entries.push(Entry::new_dir(dir));
+        }
+    }
 
-    new_entries.sort_by(|a, b| a.path.as_str().cmp(b.path.as_str()));
-    entries_after_apply.sort_by(|a, b| a.path.as_str().cmp(b.path.as_str()));
+    new_entries.sort_by(|a, b| a.path.cmp(&b.path));
+    entries_after_apply.sort_by(|a, b| a.path.cmp(&b.path));
     assert_eq!(entries_after_apply, new_entries);
 }
 
-pub fn apply(entries: &Vec<Entry>, ops: &Vec<FsOp<'_>>) -> Vec<Entry> {
-    let mut fs = HashMap::<String, Option<u64>>::new();
+pub fn apply(entries: &[Entry], ops: &[FsOp]) -> Vec<Entry> {
+    let mut fs = HashMap::<PathBuf, Entry>::new();
     for entry in entries {
-        assert_eq!(fs.insert(entry.path.clone(), Some(entry.id.unwrap())), None);
+        assert_eq!(fs.insert(entry.path.clone(), entry.clone()), None);
     }
     for op in ops {
         match op {
             FsOp::CreateFile { path } => {
-                assert!(!fs.contains_key(path.as_ref()));
-                fs.insert(path.to_string(), None);
+                assert!(!fs.contains_key(path));
+                fs.insert(path.to_path_buf(), Entry::new(None, path.to_path_buf()));
+            }
+            FsOp::CreateDir { path } => {
+                assert!(!fs.contains_key(path));
+                fs.insert(path.to_path_buf(), Entry::new_dir(path.to_path_buf()));
+            }
+            FsOp::CreateSymlink { path, target } => {
+                assert!(!fs.contains_key(path));
+                fs.insert(
+                    path.to_path_buf(),
+                    Entry::new_symlink(None, path.to_path_buf(), target.to_path_buf()),
+                );
+            }
+            FsOp::RetargetSymlink { path, target } => {
+                let entry = fs.get_mut(path).unwrap();
+                assert!(matches!(entry.kind, EntryKind::Symlink { .. }));
+                entry.kind = EntryKind::Symlink {
+                    target: target.to_path_buf(),
+                };
             }
             FsOp::MoveFile { src, dst } => {
-                assert!(fs.contains_key(src.as_ref()));
-                assert!(!fs.contains_key(dst.as_ref()));
-                let maybe_id = fs.remove(src.as_ref()).unwrap();
-                fs.insert(dst.to_string(), maybe_id);
+                assert!(!fs.contains_key(dst));
+                let mut entry = fs.remove(src).unwrap();
+                assert!(!entry.is_dir());
+                entry.path = dst.to_path_buf();
+                fs.insert(dst.to_path_buf(), entry);
+            }
+            FsOp::MoveDir { src, dst } => {
+                assert!(!fs.contains_key(dst));
+                // a directory cannot go under a file
+                assert!(!ancestors(dst).any(|a| fs.get(a).is_some_and(|e| !e.is_dir())));
+                assert!(fs.get(src).unwrap().is_dir());
+                let moved_paths = fs
+                    .keys()
+                    .filter(|p| p.starts_with(src))
+                    .cloned()
+                    .collect::<Vec<PathBuf>>();
+                for path in moved_paths {
+                    let mut entry = fs.remove(&path).unwrap();
+                    entry.path = moved_path(&path, src, dst);
+                    fs.insert(entry.path.clone(), entry);
+                }
             }
             FsOp::CopyFile { src, dst } => {
-                assert!(fs.contains_key(src.as_ref()));
-                assert!(!fs.contains_key(dst.as_ref()));
-                let maybe_id = fs.get(src.as_ref()).unwrap().clone();
-                fs.insert(dst.to_string(), maybe_id);
+                assert!(!fs.contains_key(dst));
+                let mut entry = fs.get(src).unwrap().clone();
+                assert!(!entry.is_dir());
+                entry.path = dst.to_path_buf();
+                fs.insert(dst.to_path_buf(), entry);
+            }
+            FsOp::HardLink { src, dst } => {
+                assert!(!fs.contains_key(dst));
+                let mut entry = fs.get(src).unwrap().clone();
+                assert!(!entry.is_dir());
+                entry.path = dst.to_path_buf();
+                fs.insert(dst.to_path_buf(), entry);
+            }
+            FsOp::SetPermissions { path, mode } => {
+                fs.get_mut(path).unwrap().mode = Some(*mode);
+            }
+            FsOp::Chown { path, user, group } => {
+                fs.get_mut(path).unwrap().owner = Some(Owner {
+                    user: user.to_string(),
+                    group: group.to_string(),
+                });
             }
-            FsOp::RemoveFile { path } => {
-                assert!(fs.contains_key(path.as_ref()));
-                fs.remove(path.as_ref());
+            FsOp::RemoveFile { path } | FsOp::TrashFile { path } => {
+                let entry = fs.remove(path).unwrap();
+                assert!(!entry.is_dir());
+            }
+            FsOp::RemoveDir { path } | FsOp::PruneDir { path } => {
+                let entry = fs.remove(path).unwrap();
+                assert!(entry.is_dir());
+                assert!(!fs.keys().any(|p| p.starts_with(path)));
             }
         }
     }
-    fs.into_iter()
-        .map(|(path, maybe_id)| -> Entry { Entry::new(maybe_id, path) })
-        .collect::<Vec<_>>()
+    fs.into_values().collect::<Vec<_>>()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fsutils::tmpfile::TmpDir;
+    use std::process;
     #[test]
     fn no_change() -> Result<()> {
         diff_and_apply_ops(
-            &vec![Entry::new(Some(1), "a.txt".to_string())],
-            &vec![Entry::new(Some(1), "a.txt".to_string())],
+            &[Entry::new(Some(1), "a.txt".to_string())],
+            &[Entry::new(Some(1), "a.txt".to_string())],
         )
     }
 
     #[test]
     fn test_create_1_new_file() -> Result<()> {
         diff_and_apply_ops(
-            &vec![entry(1, "a.txt")],
-            &vec![entry(1, "a.txt"), new_entry("b.txt")],
+            &[entry(1, "a.txt")],
+            &[entry(1, "a.txt"), new_entry("b.txt")],
         )
     }
 
     #[test]
     fn test_remove_1_file() -> Result<()> {
-        diff_and_apply_ops(&vec![entry(1, "a.txt")], &vec![])
+        diff_and_apply_ops(&[entry(1, "a.txt")], &[])
     }
 
     #[test]
     fn test_copy_an_existing_file() -> Result<()> {
         diff_and_apply_ops(
-            &vec![entry(1, "a.txt")],
-            &vec![entry(1, "a.txt"), entry(1, "b.txt")],
+            &[entry(1, "a.txt")],
+            &[entry(1, "a.txt"), entry(1, "b.txt")],
         )
     }
 
     #[test]
     fn test_copy_an_existing_file_rev() -> Result<()> {
         diff_and_apply_ops(
-            &vec![entry(2, "b.txt")],
-            &vec![entry(2, "a.txt"), entry(2, "b.txt")],
+            &[entry(2, "b.txt")],
+            &[entry(2, "a.txt"), entry(2, "b.txt")],
         )
     }
 
     #[test]
     fn test_user_input_invalid_id() {
         let result = diff_and_apply_ops(
-            &vec![entry(1, "a.txt")], // there was previously no entry with id 2
-            &vec![entry(1, "a.txt"), entry(2, "b.txt")],
+            &[entry(1, "a.txt")], // there was previously no entry with id 2
+            &[entry(1, "a.txt"), entry(2, "b.txt")],
         );
         assert!(result.is_err());
         let err = result.unwrap_err();
@@ -370,27 +1202,460 @@ mod tests {
     #[test]
     fn test_copy_dependency_without_cycle() -> Result<()> {
         diff_and_apply_ops(
-            &vec![entry(1, "a.txt"), entry(2, "b.txt")],
-            &vec![entry(1, "a.txt"), entry(1, "b.txt"), entry(2, "c.txt")],
+            &[entry(1, "a.txt"), entry(2, "b.txt")],
+            &[entry(1, "a.txt"), entry(1, "b.txt"), entry(2, "c.txt")],
         )
     }
 
     #[test]
     fn test_copy_dependency_without_cycle_rev() -> Result<()> {
         diff_and_apply_ops(
-            &vec![entry(2, "b.txt"), entry(3, "c.txt")],
-            &vec![entry(2, "a.txt"), entry(3, "b.txt"), entry(3, "c.txt")],
+            &[entry(2, "b.txt"), entry(3, "c.txt")],
+            &[entry(2, "a.txt"), entry(3, "b.txt"), entry(3, "c.txt")],
         )
     }
 
     #[test]
     fn test_copy_dependency_with_cycle_of_2() -> Result<()> {
         diff_and_apply_ops(
-            &vec![entry(1, "a.txt"), entry(2, "b.txt")],
-            &vec![entry(2, "a.txt"), entry(1, "b.txt")],
+            &[entry(1, "a.txt"), entry(2, "b.txt")],
+            &[entry(2, "a.txt"), entry(1, "b.txt")],
+        )
+    }
+
+    #[test]
+    fn test_create_nested_dir() -> Result<()> {
+        diff_and_apply_ops(&[dir("a")], &[dir("a"), dir("build/out")])
+    }
+
+    #[test]
+    fn test_remove_empty_dir() -> Result<()> {
+        diff_and_apply_ops(&[dir("tmp"), entry(1, "a.txt")], &[entry(1, "a.txt")])
+    }
+
+    #[test]
+    fn test_remove_dir_with_files() -> Result<()> {
+        diff_and_apply_ops(
+            &[
+                dir("a"),
+                dir("a/b"),
+                entry(1, "a/b/x.txt"),
+                entry(2, "a/y.txt"),
+            ],
+            &[entry(2, "y.txt")],
+        )
+    }
+
+    #[test]
+    fn test_rename_dir() -> Result<()> {
+        diff_and_apply_ops(
+            &[dir("a"), entry(1, "a/x.txt"), entry(2, "a/y.txt")],
+            &[dir("b"), entry(1, "b/x.txt"), entry(2, "b/y.txt")],
+        )
+    }
+
+    #[test]
+    fn test_replace_file_with_dir() -> Result<()> {
+        diff_and_apply_ops(
+            &[entry(1, "a"), entry(2, "b")],
+            &[entry(1, "a/x"), entry(2, "a/y")],
+        )
+    }
+
+    #[test]
+    fn test_replace_dir_with_file() -> Result<()> {
+        diff_and_apply_ops(&[dir("a"), entry(1, "a/x")], &[entry(1, "a")])
+    }
+
+    #[test]
+    fn test_move_dir_with_single_rename() -> Result<()> {
+        let old_entries = [
+            dir("src"),
+            dir("src/old"),
+            dir("src/old/sub"),
+            entry(1, "src/old/a.rs"),
+            entry(2, "src/old/sub/b.rs"),
+        ];
+        let new_entries = [
+            dir("src"),
+            dir("src/new"),
+            dir("src/new/sub"),
+            entry(1, "src/new/a.rs"),
+            entry(2, "src/new/sub/b.rs"),
+        ];
+        diff_and_apply_ops(&old_entries, &new_entries)?;
+        let ops = diff(&old_entries, &new_entries)?;
+        assert!(matches!(
+            ops.as_slice(),
+            [FsOp::MoveDir { src, dst }]
+                if *src == Path::new("src/old") && *dst == Path::new("src/new")
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_dir_and_change_its_content() -> Result<()> {
+        diff_and_apply_ops(
+            &[dir("a"), entry(1, "a/x"), entry(2, "a/y"), entry(3, "z")],
+            &[
+                dir("b"),
+                entry(1, "b/x"),
+                entry(2, "b/y"),
+                entry(3, "b/z"),
+                new_entry("b/new"),
+                new_entry("a/new"),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_partially_moved_dir_is_not_renamed() -> Result<()> {
+        let old_entries = [dir("a"), entry(1, "a/x"), entry(2, "a/y")];
+        let new_entries = [entry(1, "b/x"), entry(2, "c/y")];
+        diff_and_apply_ops(&old_entries, &new_entries)?;
+        let ops = diff(&old_entries, &new_entries)?;
+        assert!(!ops.iter().any(|op| matches!(op, FsOp::MoveDir { .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dir_with_copied_file_is_not_renamed() -> Result<()> {
+        let old_entries = [dir("a"), entry(1, "a/x")];
+        let new_entries = [entry(1, "b/x"), entry(1, "c")];
+        diff_and_apply_ops(&old_entries, &new_entries)?;
+        let ops = diff(&old_entries, &new_entries)?;
+        assert!(!ops.iter().any(|op| matches!(op, FsOp::MoveDir { .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_dir_into_existing_dir() -> Result<()> {
+        diff_and_apply_ops(
+            &[dir("a"), dir("b"), entry(1, "a/x"), entry(2, "b/y")],
+            &[dir("b"), entry(1, "b/x"), entry(2, "b/y")],
+        )
+    }
+
+    #[test]
+    fn test_merge_two_dirs_into_new_dir() -> Result<()> {
+        diff_and_apply_ops(
+            &[dir("a"), dir("b"), entry(1, "a/x"), entry(2, "b/y")],
+            &[dir("c"), entry(1, "c/x"), entry(2, "c/y")],
+        )
+    }
+
+    #[test]
+    fn test_swap_dirs() -> Result<()> {
+        diff_and_apply_ops(
+            &[dir("a"), dir("b"), entry(1, "a/x"), entry(2, "b/y")],
+            &[dir("a"), dir("b"), entry(1, "b/x"), entry(2, "a/y")],
+        )
+    }
+
+    #[test]
+    fn test_prune_dirs_left_empty() {
+        let old_entries = [
+            dir("a"),
+            dir("a/b"),
+            dir("a/b/c"),
+            dir("a/empty"),
+            dir("d"),
+            entry(1, "a/b/c/x"),
+            entry(2, "d/y"),
+        ];
+        let new_entries = [
+            dir("a"),
+            dir("a/b"),
+            dir("a/b/c"),
+            dir("a/empty"),
+            dir("d"),
+            entry(1, "x"),
+            entry(2, "d/y"),
+        ];
+        let pruned = prune_ops(&old_entries, &new_entries)
+            .into_iter()
+            .map(|op| match op {
+                FsOp::PruneDir { path } => path.to_str().unwrap().to_string(),
+                op => panic!("unexpected op {op:?}"),
+            })
+            .collect::<Vec<_>>();
+        // a/empty was already empty, so a stays too
+        assert_eq!(pruned, ["a/b/c", "a/b"]);
+    }
+
+    #[test]
+    fn test_prune_keeps_dirs_with_new_content() {
+        let old_entries = [dir("a"), entry(1, "a/x")];
+        let new_entries = [dir("a"), entry(1, "x"), new_entry("a/y")];
+        assert!(prune_ops(&old_entries, &new_entries).is_empty());
+    }
+
+    #[test]
+    fn test_create_symlink() -> Result<()> {
+        diff_and_apply_ops(&[entry(1, "a")], &[entry(1, "a"), new_symlink("b", "a")])
+    }
+
+    #[test]
+    fn test_move_copy_and_retarget_symlink() -> Result<()> {
+        diff_and_apply_ops(
+            &[entry(1, "a"), symlink(2, "b", "a")],
+            &[
+                entry(1, "a"),
+                symlink(2, "c", "a"),
+                symlink(2, "d/e", "../a"),
+            ],
         )
     }
 
+    #[test]
+    fn test_retarget_symlink_in_place() -> Result<()> {
+        diff_and_apply_ops(&[symlink(1, "a", "x")], &[symlink(1, "a", "y")])
+    }
+
+    #[test]
+    fn test_user_input_file_turned_into_symlink() {
+        let result = diff_and_apply_ops(&[entry(1, "a")], &[symlink(1, "a", "b")]);
+        assert!(matches!(result, Err(TreeEditError::InvalidEntry(_))))
+    }
+
+    #[test]
+    fn test_hard_link_instead_of_copy() -> Result<()> {
+        let old_entries = [entry(1, "a")];
+        let new_entries = [entry(1, "b"), hard_link(1, "c")];
+        diff_and_apply_ops(&old_entries, &new_entries)?;
+        let ops = diff(&old_entries, &new_entries)?;
+        assert!(matches!(
+            ops.as_slice(),
+            [FsOp::MoveFile { .. }, FsOp::HardLink { src, dst }]
+                if *src == Path::new("b") && *dst == Path::new("c")
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_hard_links_without_plain_line() -> Result<()> {
+        diff_and_apply_ops(
+            &[entry(1, "a"), entry(2, "b")],
+            &[hard_link(1, "a"), hard_link(1, "b"), hard_link(2, "c")],
+        )
+    }
+
+    #[test]
+    fn test_rename_and_change_mode() -> Result<()> {
+        let old_entries = [
+            with_mode(dir("bin"), 0o755),
+            with_mode(entry(1, "bin/run.sh"), 0o644),
+            with_mode(entry(2, "config"), 0o644),
+        ];
+        let new_entries = [
+            with_mode(dir("scripts"), 0o700),
+            with_mode(entry(1, "scripts/run.sh"), 0o755),
+            entry(2, "config"),
+        ];
+        diff_and_apply_ops(&old_entries, &new_entries)?;
+        let ops = diff(&old_entries, &new_entries)?;
+        let chmods = ops
+            .iter()
+            .filter_map(|op| match op {
+                FsOp::SetPermissions { path, mode } => Some((path.to_str().unwrap(), *mode)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(chmods, [("scripts", 0o700), ("scripts/run.sh", 0o755)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_moved_dir_keeps_its_mode() -> Result<()> {
+        let old_entries = [with_mode(dir("a"), 0o700), entry(1, "a/x")];
+        let new_entries = [with_mode(dir("b"), 0o700), entry(1, "b/x")];
+        diff_and_apply_ops(&old_entries, &new_entries)?;
+        let ops = diff(&old_entries, &new_entries)?;
+        assert!(matches!(ops.as_slice(), [FsOp::MoveDir { .. }]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_change_owner() -> Result<()> {
+        let old_entries = [
+            with_owner(dir("shared"), "0:0"),
+            with_owner(entry(1, "shared/data"), "0:0"),
+            with_owner(entry(2, "shared/notes"), "0:0"),
+        ];
+        let new_entries = [
+            with_owner(dir("shared"), "0:0"),
+            with_owner(entry(1, "shared/data"), "1000:100"),
+            with_owner(entry(2, "shared/todo"), "0:0"),
+        ];
+        diff_and_apply_ops(&old_entries, &new_entries)?;
+        let ops = diff(&old_entries, &new_entries)?;
+        let chowns = ops
+            .iter()
+            .filter_map(|op| match op {
+                FsOp::Chown { path, user, group } => {
+                    Some((path.to_str().unwrap(), user.as_ref(), group.as_ref()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(chowns, [("shared/data", "1000", "100")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_owner() {
+        let old_entries = [with_owner(entry(1, "a"), "0:0")];
+        let new_entries = [with_owner(entry(1, "b"), "no-such-user:0")];
+        assert!(matches!(
+            diff(&old_entries, &new_entries),
+            Err(TreeEditError::UnknownUser(user)) if user == "no-such-user"
+        ));
+        let new_entries = [with_owner(entry(1, "a"), "0:no-such-group")];
+        assert!(matches!(
+            diff(&old_entries, &new_entries),
+            Err(TreeEditError::UnknownGroup(group)) if group == "no-such-group"
+        ));
+    }
+
+    #[test]
+    fn test_non_utf8_paths() -> Result<()> {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+        let path = |bytes: &[u8]| PathBuf::from(OsStr::from_bytes(bytes));
+        let old_entries = [
+            Entry::new_dir(path(b"d\xff")),
+            Entry::new(Some(1), path(b"d\xff/a\xfe")),
+            Entry::new(Some(2), path(b"d\xff/b")),
+        ];
+        let new_entries = [
+            Entry::new_dir(path(b"d\xff")),
+            Entry::new(Some(1), path(b"d\xff/b")),
+            Entry::new(Some(2), path(b"d\xff/a\xfe")),
+        ];
+        diff_and_apply_ops(&old_entries, &new_entries)?;
+        // lossy conversion would have turned both names into the same path
+        let new_entries = [
+            Entry::new(Some(1), path(b"\xfe")),
+            Entry::new(None, path(b"\xff")),
+        ];
+        diff_and_apply_ops(&old_entries[1..2], &new_entries)
+    }
+
+    #[test]
+    fn test_normalize() {
+        for (path, expected) in [
+            ("./src/x.rs", "src/x.rs"),
+            ("src//x.rs", "src/x.rs"),
+            ("src/", "src"),
+            ("./src/../x.rs", "x.rs"),
+            ("a/b/../../c", "c"),
+            ("../x", "../x"),
+            ("a/../../x", "../x"),
+            ("/a/./b/", "/a/b"),
+        ] {
+            assert_eq!(normalize(Path::new(path)), Path::new(expected), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_listed_sources() {
+        let path = PathBuf::from;
+        let ops = [
+            // swapping a and b
+            FsOp::MoveFile {
+                src: path("a"),
+                dst: path("tmp"),
+            },
+            FsOp::MoveFile {
+                src: path("b"),
+                dst: path("a"),
+            },
+            FsOp::MoveFile {
+                src: path("tmp"),
+                dst: path("b"),
+            },
+            FsOp::CopyFile {
+                src: path("a"),
+                dst: path("c"),
+            },
+            FsOp::RemoveFile { path: path("d") },
+            FsOp::MoveDir {
+                src: path("e"),
+                dst: path("f"),
+            },
+            FsOp::SetPermissions {
+                path: path("e/x"),
+                mode: 0o644,
+            },
+            FsOp::CreateFile { path: path("g") },
+        ];
+        let expected = ["a", "b", "d", "e"].map(Path::new);
+        assert_eq!(listed_sources(&ops), expected);
+    }
+
+    #[test]
+    fn test_normalized_paths_are_unchanged() -> Result<()> {
+        let mut old_entries = [dir("./src"), entry(1, "./src/x.rs"), entry(2, "./y.rs")];
+        let mut new_entries = [dir("src/"), entry(1, "src//x.rs"), entry(2, "src/../y.rs")];
+        normalize_entries(&mut old_entries);
+        normalize_entries(&mut new_entries);
+        assert!(diff(&old_entries, &new_entries)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_dir_into_removed_file() -> Result<()> {
+        let old_entries = [entry(0, "a"), dir("b"), dir("b/c"), entry(1, "b/c/x")];
+        let new_entries = [dir("a/b/c"), entry(1, "a/b/c/x")];
+        diff_and_apply_ops(&old_entries, &new_entries)?;
+        // the file must be out of the way before anything goes under it
+        let tmp = TmpDir::new("tree-edit-move-dir")?;
+        let root = tmp.path();
+        fs::create_dir_all(root.join("b/c"))?;
+        fs::write(root.join("a"), "a")?;
+        fs::write(root.join("b/c/x"), "x")?;
+        for op in diff(&old_entries, &new_entries)? {
+            fsutils::fsop::exec(&rooted(root, &op))?;
+        }
+        assert_eq!(fs::read_to_string(root.join("a/b/c/x"))?, "x");
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_outside_root() -> Result<()> {
+        let old_entries = [entry(1, "a"), entry(2, "b")];
+        for path in ["../a", "/tmp/a", "x/../../a"] {
+            let mut new_entries = [entry(1, path), entry(2, "b")];
+            normalize_entries(&mut new_entries);
+            assert!(matches!(
+                diff(&old_entries, &new_entries),
+                Err(TreeEditError::PathOutsideRoot(_))
+            ));
+            let ops = diff_entries(&old_entries, &new_entries, true)?;
+            verify(&old_entries, &new_entries, &ops);
+            // no directory outside the root is ever created
+            assert!(matches!(ops.as_slice(), [FsOp::MoveFile { .. }]));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_input_dir_with_id() {
+        let result = diff_and_apply_ops(
+            &[entry(1, "a")],
+            &[Entry {
+                id: Some(1),
+                ..dir("a")
+            }],
+        );
+        assert!(matches!(result, Err(TreeEditError::InvalidEntry(_))))
+    }
+
+    #[test]
+    fn test_user_input_file_as_parent_dir() {
+        let result = diff_and_apply_ops(&[entry(1, "a")], &[entry(1, "a"), new_entry("a/b")]);
+        assert!(matches!(result, Err(TreeEditError::DuplicatePath(_))))
+    }
+
     fn entry(id: u64, path: &str) -> Entry {
         Entry::new(Some(id), String::from(path))
     }
@@ -399,7 +1664,141 @@ mod tests {
         Entry::new(None, String::from(path))
     }
 
-    fn diff_and_apply_ops(old_entries: &Vec<Entry>, new_entries: &Vec<Entry>) -> Result<()> {
+    fn with_mode(entry: Entry, mode: u32) -> Entry {
+        Entry {
+            mode: Some(mode),
+            ..entry
+        }
+    }
+
+    fn with_owner(entry: Entry, owner: &str) -> Entry {
+        let (user, group) = owner.split_once(':').unwrap();
+        Entry {
+            owner: Some(Owner {
+                user: user.to_string(),
+                group: group.to_string(),
+            }),
+            ..entry
+        }
+    }
+
+    fn hard_link(id: u64, path: &str) -> Entry {
+        Entry {
+            hard_link: true,
+            ..entry(id, path)
+        }
+    }
+
+    fn symlink(id: u64, path: &str, target: &str) -> Entry {
+        Entry::new_symlink(Some(id), String::from(path), String::from(target))
+    }
+
+    fn new_symlink(path: &str, target: &str) -> Entry {
+        Entry::new_symlink(None, String::from(path), String::from(target))
+    }
+
+    fn dir(path: &str) -> Entry {
+        Entry::new_dir(String::from(path))
+    }
+
+    // the same operation on paths inside the root directory
+    fn rooted(root: &Path, op: &FsOp) -> FsOp {
+        let path = |path: &PathBuf| root.join(path);
+        match op {
+            FsOp::CreateFile { path: p } => FsOp::CreateFile { path: path(p) },
+            FsOp::CreateDir { path: p } => FsOp::CreateDir { path: path(p) },
+            FsOp::CreateSymlink { path: p, target } => FsOp::CreateSymlink {
+                path: path(p),
+                target: target.clone(),
+            },
+            FsOp::RetargetSymlink { path: p, target } => FsOp::RetargetSymlink {
+                path: path(p),
+                target: target.clone(),
+            },
+            FsOp::MoveFile { src, dst } => FsOp::MoveFile {
+                src: path(src),
+                dst: path(dst),
+            },
+            FsOp::MoveDir { src, dst } => FsOp::MoveDir {
+                src: path(src),
+                dst: path(dst),
+            },
+            FsOp::CopyFile { src, dst } => FsOp::CopyFile {
+                src: path(src),
+                dst: path(dst),
+            },
+            FsOp::HardLink { src, dst } => FsOp::HardLink {
+                src: path(src),
+                dst: path(dst),
+            },
+            FsOp::SetPermissions { path: p, mode } => FsOp::SetPermissions {
+                path: path(p),
+                mode: *mode,
+            },
+            FsOp::Chown {
+                path: p,
+                user,
+                group,
+            } => FsOp::Chown {
+                path: path(p),
+                user: user.clone(),
+                group: group.clone(),
+            },
+            FsOp::RemoveFile { path: p } => FsOp::RemoveFile { path: path(p) },
+            FsOp::TrashFile { path: p } => FsOp::TrashFile { path: path(p) },
+            FsOp::RemoveDir { path: p } => FsOp::RemoveDir { path: path(p) },
+            FsOp::PruneDir { path: p } => FsOp::PruneDir { path: path(p) },
+        }
+    }
+
+    #[test]
+    fn test_confirm_with_yes() -> io::Result<()> {
+        let yes = Options {
+            yes: true,
+            ..Default::default()
+        };
+        assert!(confirm_with(&yes, || panic!("asked with --yes"))?);
+        let options = Options::default();
+        assert!(confirm_with(&options, || Ok(true))?);
+        assert!(!confirm_with(&options, || Ok(false))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_uncommitted_removals() -> Result<()> {
+        let tmp = TmpDir::new("tree-edit-uncommitted")?;
+        let dir = tmp.path();
+        let git = |args: &[&str]| {
+            let status = process::Command::new("git")
+                .arg("-C")
+                .arg(dir)
+                .args(["-c", "user.name=a", "-c", "user.email=a@b"])
+                .args(args)
+                .status();
+            assert!(status.is_ok_and(|status| status.success()));
+        };
+        git(&["init", "-q"]);
+        for name in ["clean", "changed", ".gitignore"] {
+            fs::write(dir.join(name), "ignored\n")?;
+        }
+        git(&["add", "."]);
+        git(&["commit", "-qm", "a"]);
+        fs::write(dir.join("changed"), "changed")?;
+        fs::write(dir.join("untracked"), "")?;
+        fs::write(dir.join("ignored"), "")?;
+        let status = Git::discover(dir).unwrap().status()?;
+        let ops = ["clean", "changed", "untracked", "ignored"].map(|name| FsOp::TrashFile {
+            path: PathBuf::from(name),
+        });
+        // git has no copy of what is in changed and untracked
+        assert_eq!(
+            uncommitted_removals(&ops, &status),
+            [Path::new("changed"), Path::new("untracked")]
+        );
+        Ok(())
+    }
+
+    fn diff_and_apply_ops(old_entries: &[Entry], new_entries: &[Entry]) -> Result<()> {
         let ops = diff(old_entries, new_entries)?;
         println!("old: {old_entries:?}");
         println!("new: {new_entries:?}");
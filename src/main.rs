@@ -1,10 +1,13 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 #[command(version, about = "Edit file system tree using a text editor")]
 struct CliArg {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Directory to operate on, default to current working directory
     dir: Option<PathBuf>,
 
@@ -15,6 +18,86 @@ struct CliArg {
     /// Include hidden files
     #[arg(long)]
     hidden: bool,
+
+    /// Remove directories left empty by the changes, even if their line was kept
+    #[arg(long)]
+    prune_empty_dirs: bool,
+
+    /// Show entries as an indented tree instead of full paths
+    #[arg(long)]
+    tree: bool,
+
+    /// Show an editable permission column
+    #[arg(long)]
+    mode: bool,
+
+    /// Show an editable user:group column
+    #[arg(long)]
+    owner: bool,
+
+    /// Show read-only metadata after each entry, any of size, mtime and type
+    #[arg(long, value_delimiter = ',')]
+    columns: Vec<tree_edit::Column>,
+
+    /// Allow moving and creating files outside of the directory
+    #[arg(long)]
+    allow_outside_root: bool,
+
+    /// Delete files instead of moving them to the trash, undo keeps them for the last 20 sessions
+    #[arg(long)]
+    permanent: bool,
+
+    /// Show the operations and check them against the file system without applying them
+    #[arg(long)]
+    dry_run: bool,
+
+    /// How to show the operations, text, json or sh
+    #[arg(long, default_value = "text")]
+    output: tree_edit::Output,
+
+    /// Show the operations as a shell script, same as --output sh
+    #[arg(long, conflicts_with = "output")]
+    emit_sh: bool,
+
+    /// Read the edited listing from a file instead of opening an editor, - for stdin
+    #[arg(long, value_name = "FILE")]
+    edit_script: Option<PathBuf>,
+
+    /// Print the listing that would be opened in the editor and exit
+    #[arg(long)]
+    print_listing: bool,
+
+    /// Apply the changes without asking for confirmation
+    #[arg(long, short, global = true)]
+    yes: bool,
+
+    /// Update the git index when moving or removing tracked files: auto, always or never
+    #[arg(long, default_value = "auto", global = true)]
+    git: tree_edit::GitMode,
+
+    /// Also compare file contents, not only metadata, to detect changes made while editing
+    #[arg(long)]
+    hash: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Revert the last changes applied to the directory
+    ///
+    /// Only the last 20 sessions are kept, along with the files deleted with --permanent
+    Undo,
+    /// Finish changes to the directory that were cut short
+    Resume {
+        /// Revert the part that was applied instead
+        #[arg(long)]
+        rollback: bool,
+    },
+    /// Apply a plan saved with --output json
+    Apply {
+        /// The plan file
+        #[arg(long)]
+        plan: PathBuf,
+    },
 }
 
 fn collect_files(respect_git_ignore: bool, ignore_hidden: bool) -> Vec<PathBuf> {
@@ -22,12 +105,10 @@ fn collect_files(respect_git_ignore: bool, ignore_hidden: bool) -> Vec<PathBuf>
         .git_ignore(respect_git_ignore)
         .hidden(ignore_hidden)
         .build()
-        .into_iter()
         .filter_map(|result| match result {
-            Ok(dir_entry) => match dir_entry.file_type() {
-                Some(file_type) if !file_type.is_dir() => Some(PathBuf::from(dir_entry.path())),
-                _ => None,
-            },
+            // skip the root directory itself
+            Ok(dir_entry) if dir_entry.depth() == 0 => None,
+            Ok(dir_entry) => Some(PathBuf::from(dir_entry.path())),
             Err(err) => {
                 eprintln!("{err}");
                 None
@@ -37,12 +118,62 @@ fn collect_files(respect_git_ignore: bool, ignore_hidden: bool) -> Vec<PathBuf>
 }
 
 fn main() -> anyhow::Result<()> {
-    let args: CliArg = CliArg::parse();
+    let mut args: CliArg = CliArg::parse();
+    // the plan file and edit script are given relative to where the command was run from
+    if let Some(Command::Apply { plan }) = &mut args.command {
+        *plan = std::path::absolute(&plan)?;
+    }
+    if let Some(script) = args.edit_script.as_mut().filter(|s| *s != Path::new("-")) {
+        *script = std::path::absolute(&script)?;
+    }
     if let Some(ref dir) = args.dir {
         // no need to reset, app exit right after anyway
         env::set_current_dir(dir)?;
     }
-    let paths = collect_files(!args.no_git_ignore, !args.hidden);
-    tree_edit::tree_edit(&paths)?;
+    let mut filters = Vec::new();
+    if !args.no_git_ignore {
+        filters.push(String::from(".gitignore"));
+    }
+    if !args.hidden {
+        filters.push(String::from("no hidden files"));
+    }
+    let options = tree_edit::Options {
+        prune_empty_dirs: args.prune_empty_dirs,
+        format: if args.tree {
+            tree_edit::BufferFormat::Tree
+        } else {
+            tree_edit::BufferFormat::Flat
+        },
+        mode_column: args.mode,
+        owner_column: args.owner,
+        columns: args.columns,
+        root: env::current_dir()?,
+        filters,
+        allow_outside_root: args.allow_outside_root,
+        permanent: args.permanent,
+        dry_run: args.dry_run,
+        output: if args.emit_sh {
+            tree_edit::Output::Sh
+        } else {
+            args.output
+        },
+        edit_script: args.edit_script,
+        yes: args.yes,
+        git: args.git,
+        hash_contents: args.hash,
+    };
+    match args.command {
+        Some(Command::Undo) => tree_edit::undo(&options)?,
+        Some(Command::Resume { rollback }) => tree_edit::resume(&options, rollback)?,
+        Some(Command::Apply { plan }) => tree_edit::apply_plan(&plan, &options)?,
+        None => {
+            let paths = collect_files(!args.no_git_ignore, !args.hidden);
+            if args.print_listing {
+                tree_edit::print_listing(&paths, &options)?
+            } else {
+                tree_edit::tree_edit(&paths, &options)?
+            }
+        }
+    }
     Ok(())
 }
@@ -1,21 +1,127 @@
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::{env, fs, io, process};
 
-use crate::entry::Entry;
+use crate::entry::{Entry, EntryKind, Info, Owner};
 use crate::error::TreeEditError;
-use crate::fsutils::fsop::FsOp;
+use crate::fsutils::fsop::{FsOp, Plan};
+use crate::fsutils::shell;
+use crate::fsutils::time;
 use crate::fsutils::tmpfile;
 use crate::fsutils::tmpfile::TmpFile;
+use crate::Options;
 
-pub fn user_edit_entries(entries: &Vec<Entry>) -> crate::Result<Vec<Entry>> {
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum BufferFormat {
+    /// One full path per line
+    #[default]
+    Flat,
+    /// One name per line, indented under its parent directory
+    Tree,
+}
+
+const TREE_INDENT: &str = "  ";
+
+/// How the operations are shown before applying them
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Output {
+    /// Coloured, one operation per line
+    #[default]
+    Text,
+    /// The plan as JSON on stdout, see `Plan`
+    Json,
+    /// A POSIX shell script on stdout doing the same
+    Sh,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Output::Text),
+            "json" => Ok(Output::Json),
+            "sh" => Ok(Output::Sh),
+            _ => Err(format!("unknown output {}, expected text, json or sh", s)),
+        }
+    }
+}
+
+/// Read-only metadata that can be shown after each entry
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Column {
+    Size,
+    Mtime,
+    Type,
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "size" => Ok(Column::Size),
+            "mtime" => Ok(Column::Mtime),
+            "type" => Ok(Column::Type),
+            _ => Err(format!(
+                "unknown column {}, expected size, mtime or type",
+                s
+            )),
+        }
+    }
+}
+
+const COMMENT: char = '#';
+
+// everything after the last marker on a line is an annotation, and ignored
+const ANNOTATION_MARKER: &str = "  #";
+
+pub fn user_edit_entries(entries: &[Entry], options: &Options) -> crate::Result<Vec<Entry>> {
     let tmp_file = TmpFile::new(&tmpfile::get_tmp_file_name(), "txt")?;
-    fs::write(&tmp_file.path(), entries_to_str(&entries))?;
-    let exit_code = open_in_editor(&tmp_file.path())?;
+    fs::write(tmp_file.path(), entries_to_str(entries, options))?;
+    let exit_code = open_in_editor(tmp_file.path())?;
     if !exit_code.success() {
         return Err(TreeEditError::EditorExitFailure(exit_code));
     }
-    let content = fs::read_to_string(&tmp_file.path())?;
-    Ok(str_to_entries(&content))
+    let content = fs::read_to_string(tmp_file.path())?;
+    str_to_entries(&content, options, is_annotated(entries, options))
+}
+
+/// Reads an edited version of the listing of the given entries from a file,
+/// or from stdin for `-`
+pub fn read_edit_script(
+    path: &Path,
+    entries: &[Entry],
+    options: &Options,
+) -> crate::Result<Vec<Entry>> {
+    read_edit_script_from(path, io::stdin(), entries, options)
+}
+
+// `-` reads the script from stdin
+fn read_edit_script_from(
+    path: &Path,
+    stdin: impl io::Read,
+    entries: &[Entry],
+    options: &Options,
+) -> crate::Result<Vec<Entry>> {
+    let content = if path == Path::new("-") {
+        io::read_to_string(stdin)?
+    } else {
+        fs::read_to_string(path)?
+    };
+    str_to_entries(&content, options, is_annotated(entries, options))
+}
+
+pub fn print_listing(entries: &[Entry], options: &Options) -> io::Result<()> {
+    write_listing(&mut io::stdout().lock(), entries, options)
+}
+
+fn write_listing(out: &mut impl Write, entries: &[Entry], options: &Options) -> io::Result<()> {
+    writeln!(out, "{}", entries_to_str(entries, options))
 }
 
 fn digit_count(val: u64) -> u32 {
@@ -26,42 +132,577 @@ fn digit_count(val: u64) -> u32 {
     }
 }
 
-fn entries_to_str(entries: &Vec<Entry>) -> String {
-    let max_id = entries.iter().map(|e| e.id.unwrap()).max();
-    match max_id {
-        Some(max_id) => {
-            let id_col_len = digit_count(max_id) as usize;
-            entries
+fn entries_to_str(entries: &[Entry], options: &Options) -> String {
+    let body = entries_to_body(entries, options);
+    match body.is_empty() {
+        true => header(entries, options),
+        false => format!("{}\n{}", header(entries, options), body),
+    }
+}
+
+// explains the syntax, every line of it is a comment
+fn header(entries: &[Entry], options: &Options) -> String {
+    let dir_count = entries.iter().filter(|e| e.is_dir()).count();
+    let filters = match options.filters.is_empty() {
+        true => String::from("none"),
+        false => options.filters.join(", "),
+    };
+    let mut lines = vec![
+        format!(
+            "tree-edit {}: {} files, {} directories",
+            options.root.display(),
+            entries.len() - dir_count,
+            dir_count
+        ),
+        format!("filters: {}", filters),
+        String::new(),
+        String::from("Edit the lines below, save and quit to apply the changes."),
+        String::from("  <id> <path>          existing file, edit the path to move it"),
+        String::from("  <path>               new file"),
+        String::from("  <path>/              directory"),
+        String::from("  <path> -> <target>   symbolic link"),
+        String::from("  &<id> <path>         hard link to the file with the same id"),
+        String::from("Duplicate a line to copy a file, delete it to remove the file."),
+        String::from("Quote names with spaces or special characters, like \"my file\\n\"."),
+    ];
+    if options.mode_column {
+        lines.push(String::from(
+            "Permissions are written as rwxr-xr-x or 0755.",
+        ));
+    }
+    if options.owner_column {
+        lines.push(String::from("Owners are written as user:group."));
+    }
+    let has_git_status = has_git_status(entries);
+    if !options.columns.is_empty() || has_git_status {
+        lines.push(format!(
+            "Metadata after the last \"{}\" of a line is read-only.",
+            ANNOTATION_MARKER
+        ));
+    }
+    if has_git_status {
+        lines.push(String::from(
+            "Git status comes first, as in git status --short: staged, then unstaged.",
+        ));
+    }
+    lines.push(String::from(
+        "Lines starting with # are ignored, write \\# for a name starting with #.",
+    ));
+    lines
+        .into_iter()
+        .map(|l| format!("{} {}", COMMENT, l).trim_end().to_string())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn entries_to_body(entries: &[Entry], options: &Options) -> String {
+    let max_id = entries.iter().filter_map(|e| e.id).max();
+    let has_hard_link = entries.iter().any(|e| e.hard_link);
+    let id_col_len = max_id.map_or(0, digit_count) as usize + has_hard_link as usize;
+    let owner_col_len = entries
+        .iter()
+        .filter_map(|e| e.owner.as_ref())
+        .map(|o| format_owner(o).chars().count())
+        .max()
+        .unwrap_or(0);
+    let lines = match options.format {
+        BufferFormat::Flat => entries
+            .iter()
+            .map(|e| (e, String::new(), e.path.as_os_str()))
+            .collect(),
+        BufferFormat::Tree => tree_names(entries),
+    };
+    let lines = lines
+        .into_iter()
+        .map(|(e, indent, name)| {
+            let id = match e.id {
+                Some(id) if e.hard_link => format!("&{}", id),
+                Some(id) => id.to_string(),
+                None => String::new(),
+            };
+            let mut line = format!("{:<id_col_len$} ", id);
+            if options.mode_column {
+                let mode = e.mode.map(format_mode).unwrap_or_default();
+                line.push_str(&format!("{:<9} ", mode));
+            }
+            if options.owner_column {
+                let owner = e.owner.as_ref().map(format_owner).unwrap_or_default();
+                line.push_str(&format!("{:<owner_col_len$} ", owner));
+            }
+            line.push_str(&indent);
+            line.push_str(&format_name(name));
+            match &e.kind {
+                EntryKind::File => (),
+                EntryKind::Dir => line.push('/'),
+                EntryKind::Symlink { target } => {
+                    line.push_str(&format!(" -> {}", format_target(target)))
+                }
+            }
+            (e, line)
+        })
+        .collect::<Vec<(&Entry, String)>>();
+    // git status markers come first, when there are any
+    let has_git_status = has_git_status(entries);
+    let columns = has_git_status
+        .then_some(None)
+        .into_iter()
+        .chain(options.columns.iter().copied().map(Some))
+        .collect::<Vec<Option<Column>>>();
+    if columns.is_empty() {
+        return lines
+            .into_iter()
+            .map(|(_, line)| line)
+            .collect::<Vec<String>>()
+            .join("\n");
+    }
+    let annotations = lines
+        .iter()
+        .map(|(e, _)| {
+            columns
                 .iter()
-                .map(|e| format!("{:<id_col_len$} {}", e.id.unwrap(), e.path))
+                .map(|c| match (&e.info, c) {
+                    (Some(info), Some(column)) => format_info(info, e, *column),
+                    (Some(info), None) => info.git_status.clone().unwrap_or_default(),
+                    (None, _) => String::new(),
+                })
                 .collect::<Vec<String>>()
-                .join("\n")
-        }
-        None => String::from(""),
+        })
+        .collect::<Vec<Vec<String>>>();
+    let width = |texts: Vec<&String>| texts.into_iter().map(|t| t.chars().count()).max();
+    let line_width = width(lines.iter().map(|(_, l)| l).collect()).unwrap_or(0);
+    let column_widths = (0..columns.len())
+        .map(|i| width(annotations.iter().map(|a| &a[i]).collect()).unwrap_or(0))
+        .collect::<Vec<usize>>();
+    lines
+        .into_iter()
+        .zip(annotations)
+        .map(|((_, line), annotation)| {
+            let cells = annotation
+                .iter()
+                .zip(&columns)
+                .zip(&column_widths)
+                .map(|((text, column), w)| match column {
+                    Some(Column::Size) => format!("{:>w$}", text),
+                    _ => format!("{:<w$}", text),
+                })
+                .collect::<Vec<String>>();
+            let padded = format!("{:<line_width$}", line);
+            format!("{}{} {}", padded, ANNOTATION_MARKER, cells.join("  "))
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// whether the lines listing the entries end with annotations
+fn is_annotated(entries: &[Entry], options: &Options) -> bool {
+    !options.columns.is_empty() || has_git_status(entries)
+}
+
+fn has_git_status(entries: &[Entry]) -> bool {
+    entries
+        .iter()
+        .any(|e| e.info.as_ref().is_some_and(|i| i.git_status.is_some()))
+}
+
+fn format_info(info: &Info, entry: &Entry, column: Column) -> String {
+    match column {
+        // the size of a directory says nothing about its content
+        Column::Size if entry.is_dir() => String::from("-"),
+        Column::Size => format_size(info.size),
+        Column::Mtime => format_mtime(info.mtime),
+        Column::Type => info.file_type.to_string(),
     }
 }
 
-fn str_to_entries(s: &str) -> Vec<Entry> {
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+    if size < 1024 {
+        return size.to_string();
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, UNITS[unit])
+}
+
+// in local time, like `ls`
+fn format_mtime(mtime: i64) -> String {
+    let Some(tm) = time::local_time(mtime) else {
+        return mtime.to_string();
+    };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min
+    )
+}
+
+// orders entries so that they follow their parent directory, each paired with
+// the indentation of the directory depth and its path relative to that directory
+fn tree_names(entries: &[Entry]) -> Vec<(&Entry, String, &OsStr)> {
+    let dirs = entries
+        .iter()
+        .filter(|e| e.is_dir())
+        .map(|e| e.path.as_path())
+        .collect::<HashSet<&Path>>();
+    // paths are ordered by component, so children follow their parent
+    let mut sorted = entries.iter().collect::<Vec<&Entry>>();
+    sorted.sort_by(|a, b| a.path.cmp(&b.path));
+    sorted
+        .into_iter()
+        .map(|e| {
+            let mut listed_ancestors = crate::ancestors(&e.path).filter(|p| dirs.contains(p));
+            let (depth, name) = match listed_ancestors.next() {
+                Some(parent) => (
+                    1 + listed_ancestors.count(),
+                    e.path.strip_prefix(parent).unwrap().as_os_str(),
+                ),
+                None => (0, e.path.as_os_str()),
+            };
+            (e, TREE_INDENT.repeat(depth), name)
+        })
+        .collect()
+}
+
+fn str_to_entries(s: &str, options: &Options, annotated: bool) -> crate::Result<Vec<Entry>> {
+    // directories the following lines can be nested in, with the column of their name
+    let mut parents = Vec::<(usize, PathBuf)>::new();
     let lines = s.split("\n");
     lines
-        .map(|l| l.trim())
-        .filter(|l| !l.is_empty())
+        .map(|line| match annotated {
+            true => strip_annotation(line),
+            false => line,
+        })
+        .filter(|l| !l.trim().is_empty())
+        .filter(|l| !l.trim_start().starts_with(COMMENT))
         .map(|line| {
-            let mut split = line.split_whitespace();
-            let maybe_id_str = split.next();
-            let maybe_id = maybe_id_str.and_then(|id_str| id_str.parse::<u64>().ok());
-            let path = match maybe_id {
-                Some(_) => &line[(maybe_id_str.unwrap().chars().count() + 1)..],
-                None => line,
+            let (maybe_id, hard_link, rest) = split_id(line);
+            let (mode, rest) = match options.mode_column {
+                true => split_mode(rest),
+                false => (None, rest),
+            };
+            let (owner, rest) = match options.owner_column {
+                true => split_owner(rest),
+                false => (None, rest),
+            };
+            let (name, kind) = parse_name(rest.trim())?;
+            let path = match options.format {
+                BufferFormat::Flat => PathBuf::from(name),
+                BufferFormat::Tree => {
+                    let column = line.chars().count() - rest.trim_start().chars().count();
+                    while parents.last().is_some_and(|(c, _)| *c >= column) {
+                        parents.pop();
+                    }
+                    let path = match parents.last() {
+                        Some((_, parent)) => {
+                            let mut path = parent.clone().into_os_string();
+                            path.push("/");
+                            path.push(name);
+                            PathBuf::from(path)
+                        }
+                        None => PathBuf::from(name),
+                    };
+                    if kind == EntryKind::Dir {
+                        parents.push((column, path.clone()));
+                    }
+                    path
+                }
+            };
+            Ok(Entry {
+                id: maybe_id,
+                path,
+                kind,
+                mode,
+                owner,
+                info: None,
+                fingerprint: None,
+                hard_link,
+            })
+        })
+        .collect()
+}
+
+// a name starting with `#` would make its line a comment, prefix it with `\`,
+// and so for names already starting with backslashes followed by `#`
+fn escape_comment(name: &str) -> String {
+    let indent = name.len() - name.trim_start().len();
+    match name[indent..].trim_start_matches('\\').starts_with(COMMENT) {
+        true => format!("{}\\{}", &name[..indent], &name[indent..]),
+        false => name.to_string(),
+    }
+}
+
+fn unescape_comment(name: &str) -> &str {
+    match name.strip_prefix('\\') {
+        Some(rest) if rest.trim_start_matches('\\').starts_with(COMMENT) => rest,
+        _ => name,
+    }
+}
+
+// the annotation comes after the name and target, a quoted one may hold
+// the marker itself so only what follows the last closing quote is searched
+fn strip_annotation(line: &str) -> &str {
+    let mut quoted_end = 0;
+    let mut in_quote = false;
+    let mut previous = ' ';
+    let mut chars = line.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            // a quote only opens a name or target, not in the middle of one
+            '"' if !in_quote && previous.is_whitespace() => in_quote = true,
+            '"' if in_quote => {
+                in_quote = false;
+                quoted_end = i + 1;
+            }
+            '\\' if in_quote => {
+                chars.next();
+            }
+            _ => (),
+        }
+        previous = c;
+    }
+    match line[quoted_end..].rfind(ANNOTATION_MARKER) {
+        Some(i) => &line[..quoted_end + i],
+        None => line,
+    }
+}
+
+// splits the leading file id off a line, an id prefixed with `&` marks a hard link
+fn split_id(line: &str) -> (Option<u64>, bool, &str) {
+    let line = line.trim_start();
+    let id_len = line.find(char::is_whitespace).unwrap_or(line.len());
+    let (hard_link, id_str) = match line[..id_len].strip_prefix('&') {
+        Some(id_str) => (true, id_str),
+        None => (false, &line[..id_len]),
+    };
+    // ids are never written with leading zeros, those are octal modes
+    if id_str.len() > 1 && id_str.starts_with('0') {
+        return (None, false, line);
+    }
+    match id_str.parse::<u64>() {
+        Ok(id) => (Some(id), hard_link, &line[id_len..]),
+        Err(_) => (None, false, line),
+    }
+}
+
+// splits the leading permission bits off the rest of a line
+fn split_mode(rest: &str) -> (Option<u32>, &str) {
+    let trimmed = rest.trim_start();
+    let mode_len = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+    match parse_mode(&trimmed[..mode_len]) {
+        Some(mode) => (Some(mode), &trimmed[mode_len..]),
+        None => (None, rest),
+    }
+}
+
+// splits a leading `user:group` off the rest of a line, as long as a name follows
+fn split_owner(rest: &str) -> (Option<Owner>, &str) {
+    let trimmed = rest.trim_start();
+    let owner_len = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+    if trimmed[owner_len..].trim().is_empty() || trimmed.starts_with('"') {
+        return (None, rest);
+    }
+    match trimmed[..owner_len].split_once(':') {
+        Some((user, group)) if !user.is_empty() && !group.is_empty() && !group.contains(':') => {
+            let owner = Owner {
+                user: user.to_string(),
+                group: group.to_string(),
+            };
+            (Some(owner), &trimmed[owner_len..])
+        }
+        _ => (None, rest),
+    }
+}
+
+fn format_owner(owner: &Owner) -> String {
+    format!("{}:{}", owner.user, owner.group)
+}
+
+const MODE_CHARS: &[u8; 9] = b"rwxrwxrwx";
+
+// setuid, setgid and sticky bit, shown in place of the execute bit they go with
+fn special_bit(position: usize) -> u32 {
+    match position {
+        2 => 0o4000,
+        5 => 0o2000,
+        8 => 0o1000,
+        _ => 0,
+    }
+}
+
+fn format_mode(mode: u32) -> String {
+    (0..9)
+        .map(|i| {
+            let set = mode & (1 << (8 - i)) != 0;
+            let special = special_bit(i) != 0 && mode & special_bit(i) != 0;
+            match (special, set, i) {
+                (true, true, 8) => 't',
+                (true, false, 8) => 'T',
+                (true, true, _) => 's',
+                (true, false, _) => 'S',
+                (false, true, _) => MODE_CHARS[i] as char,
+                (false, false, _) => '-',
             }
-            .trim();
-            Entry::new(maybe_id, path.to_string())
         })
         .collect()
 }
 
-fn open_in_editor(path: &PathBuf) -> crate::Result<process::ExitStatus> {
-    let editors = vec![
+// accepts octal with a leading zero (`0755`) or the symbolic form (`rwxr-xr-x`)
+fn parse_mode(s: &str) -> Option<u32> {
+    if s.len() > 1 && s.starts_with('0') {
+        return u32::from_str_radix(s, 8).ok().filter(|m| *m <= 0o7777);
+    }
+    if s.len() != 9 {
+        return None;
+    }
+    let mut mode = 0;
+    for (i, c) in s.chars().enumerate() {
+        let bit = 1 << (8 - i);
+        mode |= match c {
+            '-' => 0,
+            c if c == MODE_CHARS[i] as char => bit,
+            's' if i == 2 || i == 5 => bit | special_bit(i),
+            'S' if i == 2 || i == 5 => special_bit(i),
+            't' if i == 8 => bit | special_bit(i),
+            'T' if i == 8 => special_bit(i),
+            _ => return None,
+        };
+    }
+    Some(mode)
+}
+
+// parses what follows the columns of a line: a name, then a trailing slash
+// for directories or ` -> target` for symlinks, and nothing else
+fn parse_name(s: &str) -> crate::Result<(OsString, EntryKind)> {
+    let invalid = || TreeEditError::InvalidEntry(s.to_string());
+    let (name, rest) = match unquote(s) {
+        Some((name, rest)) => (name, rest),
+        None => {
+            let end = s.find(" -> ").unwrap_or(s.len());
+            let name = s[..end].trim_end().trim_end_matches('/');
+            (OsString::from(unescape_comment(name)), &s[name.len()..])
+        }
+    };
+    let after_slash = rest.trim_start_matches('/').trim_start();
+    if let Some(target) = after_slash.strip_prefix("->") {
+        let target = target.trim();
+        let target = match unquote(target) {
+            Some((target, rest)) if rest.trim().is_empty() => PathBuf::from(target),
+            Some(_) => return Err(invalid()),
+            None => PathBuf::from(target),
+        };
+        return Ok((name, EntryKind::Symlink { target }));
+    }
+    if !after_slash.is_empty() {
+        return Err(invalid());
+    }
+    // a trailing slash marks a directory
+    match rest.starts_with('/') {
+        true => Ok((name, EntryKind::Dir)),
+        false => Ok((name, EntryKind::File)),
+    }
+}
+
+fn format_name(name: &OsStr) -> String {
+    match name.to_str() {
+        Some(name) if !needs_quotes(name) => escape_comment(name),
+        _ => quote(name),
+    }
+}
+
+// symlink targets never start a line, they need no comment escape
+fn format_target(target: &Path) -> String {
+    match target.to_str() {
+        Some(target) if !needs_quotes(target) => target.to_string(),
+        _ => quote(target.as_os_str()),
+    }
+}
+
+// names that would otherwise be mangled or read as something else are quoted,
+// and so are the ones that are not valid UTF-8
+fn needs_quotes(name: &str) -> bool {
+    name.is_empty()
+        || name.starts_with('"')
+        || name.chars().any(|c| c.is_whitespace() || c.is_control())
+        || name
+            .trim_start_matches('&')
+            .chars()
+            .all(|c| c.is_ascii_digit())
+        || parse_mode(name).is_some()
+}
+
+pub fn quote(name: &OsStr) -> String {
+    let mut quoted = String::from('"');
+    for chunk in name.as_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                '\n' => quoted.push_str("\\n"),
+                '\t' => quoted.push_str("\\t"),
+                '\r' => quoted.push_str("\\r"),
+                c if c.is_control() => quoted.push_str(&format!("\\u{{{:x}}}", c as u32)),
+                c => quoted.push(c),
+            }
+        }
+        // bytes that do not decode are kept as they are
+        for byte in chunk.invalid() {
+            quoted.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// reads a quoted name, returning it with the rest of the line,
+// or None if the name is not quoted or the quote is never closed
+pub fn unquote(s: &str) -> Option<(OsString, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut name = Vec::<u8>::new();
+    let push =
+        |name: &mut Vec<u8>, c: char| name.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+    while let Some((i, c)) = chars.next() {
+        match c {
+            // i is relative to the opening quote
+            '"' => return Some((OsString::from_vec(name), &s[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                'n' => name.push(b'\n'),
+                't' => name.push(b'\t'),
+                'r' => name.push(b'\r'),
+                'x' => {
+                    let code = chars.by_ref().take(2).map(|(_, c)| c).collect::<String>();
+                    name.push(u8::from_str_radix(&code, 16).ok()?);
+                }
+                'u' => {
+                    let code = chars
+                        .by_ref()
+                        .map(|(_, c)| c)
+                        .skip_while(|c| *c == '{')
+                        .take_while(|c| *c != '}')
+                        .collect::<String>();
+                    push(
+                        &mut name,
+                        char::from_u32(u32::from_str_radix(&code, 16).ok()?)?,
+                    );
+                }
+                c => push(&mut name, c),
+            },
+            c => push(&mut name, c),
+        }
+    }
+    None
+}
+
+fn open_in_editor(path: &Path) -> crate::Result<process::ExitStatus> {
+    let editors = [
         env::var("VISUAL").ok(),
         env::var("EDITOR").ok(),
         Some(String::from("nvim")),
@@ -71,23 +712,18 @@ fn open_in_editor(path: &PathBuf) -> crate::Result<process::ExitStatus> {
     ];
     let editors: Vec<_> = editors.iter().flatten().collect();
     for editor in editors {
-        match try_open_in_editor(path, &editor)? {
-            Some(exit_status) => return Ok(exit_status),
-            None => (),
+        if let Some(exit_status) = try_open_in_editor(path, editor)? {
+            return Ok(exit_status);
         }
     }
     Err(TreeEditError::NoEditorAvailable())
 }
 
-fn try_open_in_editor(
-    path: &PathBuf,
-    executable: &str,
-) -> crate::Result<Option<process::ExitStatus>> {
+fn try_open_in_editor(path: &Path, executable: &str) -> crate::Result<Option<process::ExitStatus>> {
     eprintln!("opening file {} in {}", path.display(), executable);
     match process::Command::new(executable)
         .arg(path.as_os_str())
         .spawn()
-        .into()
     {
         Ok(mut child) => {
             // successfully spawn child process
@@ -106,22 +742,592 @@ fn try_open_in_editor(
     }
 }
 
-pub fn display_ops(ops: &Vec<FsOp>) {
+pub fn display_ops(ops: &[FsOp]) {
     for op in ops {
         match op {
-            FsOp::CreateFile { path } => eprintln!("\x1b[32mCREATE {}\x1b[0m", path),
-            FsOp::MoveFile { src, dst } => eprintln!("\x1b[33mMOVE   {} => {}\x1b[0m", src, dst),
-            FsOp::CopyFile { src, dst } => eprintln!("\x1b[32mCOPY   {} => {}\x1b[0m", src, dst),
-            FsOp::RemoveFile { path } => eprintln!("\x1b[31mREMOVE {}\x1b[0m", path),
+            FsOp::CreateFile { path } => eprintln!("\x1b[32mCREATE {}\x1b[0m", path.display()),
+            FsOp::CreateDir { path } => eprintln!("\x1b[32mCREATE {}/\x1b[0m", path.display()),
+            FsOp::CreateSymlink { path, target } => {
+                eprintln!(
+                    "\x1b[32mCREATE {} -> {}\x1b[0m",
+                    path.display(),
+                    target.display()
+                )
+            }
+            FsOp::RetargetSymlink { path, target } => {
+                eprintln!(
+                    "\x1b[33mRELINK {} -> {}\x1b[0m",
+                    path.display(),
+                    target.display()
+                )
+            }
+            FsOp::MoveFile { src, dst } => eprintln!(
+                "\x1b[33mMOVE   {} => {}\x1b[0m",
+                src.display(),
+                dst.display()
+            ),
+            FsOp::MoveDir { src, dst } => {
+                eprintln!(
+                    "\x1b[33mMOVE   {}/ => {}/\x1b[0m",
+                    src.display(),
+                    dst.display()
+                )
+            }
+            FsOp::CopyFile { src, dst } => eprintln!(
+                "\x1b[32mCOPY   {} => {}\x1b[0m",
+                src.display(),
+                dst.display()
+            ),
+            FsOp::HardLink { src, dst } => eprintln!(
+                "\x1b[32mLINK   {} => {}\x1b[0m",
+                src.display(),
+                dst.display()
+            ),
+            FsOp::Chown { path, user, group } => {
+                eprintln!(
+                    "\x1b[33mCHOWN  {}:{} {}\x1b[0m",
+                    user,
+                    group,
+                    path.display()
+                )
+            }
+            FsOp::SetPermissions { path, mode } => {
+                eprintln!(
+                    "\x1b[33mCHMOD  {} {}\x1b[0m",
+                    format_mode(*mode),
+                    path.display()
+                )
+            }
+            FsOp::RemoveFile { path } => eprintln!("\x1b[31mREMOVE {}\x1b[0m", path.display()),
+            FsOp::TrashFile { path } => eprintln!("\x1b[31mTRASH  {}\x1b[0m", path.display()),
+            FsOp::RemoveDir { path } => eprintln!("\x1b[31mREMOVE {}/\x1b[0m", path.display()),
+            FsOp::PruneDir { path } => eprintln!("\x1b[31mPRUNE  {}/\x1b[0m", path.display()),
         }
     }
 }
 
+pub fn print_plan(plan: &Plan) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, plan)?;
+    writeln!(stdout)
+}
+
+pub fn print_script(ops: &[FsOp]) -> io::Result<()> {
+    io::stdout().lock().write_all(&shell::script(ops))
+}
+
 pub fn user_confirm() -> io::Result<bool> {
+    read_confirmation(&mut io::stdin().lock())
+}
+
+fn read_confirmation(input: &mut impl io::BufRead) -> io::Result<bool> {
     eprint!("do you want to proceed? [y/N] ");
     let mut buffer = String::new();
-    io::stdin().read_line(&mut buffer)?;
+    if input.read_line(&mut buffer)? == 0 {
+        // nothing left to read, e.g. the edit script came from stdin
+        eprintln!();
+        return Ok(false);
+    }
     let normalized = buffer.to_lowercase();
     let normalized = normalized.trim();
     Ok(normalized == "yes" || normalized == "y")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fsutils::tmpfile::TmpDir;
+    use std::io::Read;
+    use std::path::PathBuf;
+
+    fn without_header(s: &str) -> String {
+        s.lines()
+            .filter(|l| !l.starts_with(COMMENT))
+            .collect::<Vec<&str>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_comments_and_header() {
+        let options = Options {
+            format: BufferFormat::Tree,
+            root: PathBuf::from("/data"),
+            filters: vec![String::from(".gitignore")],
+            ..Default::default()
+        };
+        let entries = vec![
+            Entry::new_dir(String::from("#drafts")),
+            Entry::new(Some(0), String::from("#drafts/#1")),
+            Entry::new(Some(1), String::from("\\#2")),
+        ];
+        let s = entries_to_str(&entries, &options);
+        assert!(s.starts_with("# tree-edit /data: 2 files, 1 directories\n# filters: .gitignore\n"));
+        assert_eq!(
+            without_header(&s),
+            ["  \\#drafts/", "0   \\#1", "1 \\\\#2"].join("\n")
+        );
+        assert_eq!(str_to_entries(&s, &options, false).unwrap(), entries);
+        let edited =
+            str_to_entries("# a note\n  # indented note\n0 #drafts/#1", &options, false).unwrap();
+        assert_eq!(edited, [Entry::new(Some(0), String::from("#drafts/#1"))]);
+    }
+
+    const AWKWARD_NAMES: &[&str] = &[
+        "2024 report.txt",
+        "2024",
+        "&12",
+        " leading",
+        "trailing ",
+        "new\nline",
+        "tab\tand\rreturn",
+        "bell\u{7}",
+        "\"quoted\"",
+        "back\\slash",
+        "#hash",
+        "\\#hash",
+        "a -> b",
+        "0755",
+        "rwxr-xr-x",
+        "root:root x",
+        "  #",
+        "ünïcödé",
+    ];
+
+    #[test]
+    fn test_awkward_names_round_trip() {
+        for format in [BufferFormat::Flat, BufferFormat::Tree] {
+            let options = Options {
+                format,
+                mode_column: true,
+                owner_column: true,
+                columns: vec![Column::Type],
+                ..Default::default()
+            };
+            let mut entries = Vec::new();
+            for (i, name) in AWKWARD_NAMES.iter().enumerate() {
+                let dir = format!("dir {}", name);
+                entries.push(Entry::new_dir(dir.clone()));
+                entries.push(Entry::new(Some(2 * i as u64), format!("{}/{}", dir, name)));
+                entries.push(Entry::new_symlink(
+                    Some(2 * i as u64 + 1),
+                    format!("{}/link {}", dir, name),
+                    name.to_string(),
+                ));
+            }
+            let s = entries_to_str(&entries, &options);
+            let mut parsed = str_to_entries(&s, &options, true).unwrap();
+            // the tree format lists entries in its own order
+            parsed.sort_by(|a, b| a.path.cmp(&b.path));
+            entries.sort_by(|a, b| a.path.cmp(&b.path));
+            assert_eq!(parsed, entries, "{}", without_header(&s));
+        }
+    }
+
+    #[test]
+    fn test_non_utf8_names_round_trip() {
+        let name = |bytes: &[u8]| PathBuf::from(OsStr::from_bytes(bytes));
+        let entries = vec![
+            Entry::new_dir(name(b"caf\xe9")),
+            Entry::new(Some(0), name(b"caf\xe9/men\xfc.txt")),
+            Entry::new_symlink(Some(1), name(b"link"), name(b"caf\xe9/\xff\xfe")),
+        ];
+        for format in [BufferFormat::Flat, BufferFormat::Tree] {
+            let options = Options {
+                format,
+                ..Default::default()
+            };
+            let s = entries_to_str(&entries, &options);
+            assert!(s.contains(r#""caf\xe9""#) && s.contains(r#""caf\xe9/\xff\xfe""#));
+            assert_eq!(str_to_entries(&s, &options, false).unwrap(), entries);
+        }
+        // escapes can also be typed in, valid UTF-8 or not
+        let edited = str_to_entries(r#"0 "caf\xc3\xa9/\x80""#, &Options::default(), false).unwrap();
+        assert_eq!(edited[0].path, name(b"caf\xc3\xa9/\x80"));
+    }
+
+    #[test]
+    fn test_typed_names_with_spaces() {
+        let options = Options::default();
+        let edited = str_to_entries(
+            "\"2024 report.txt\"\n3 \" padded \"\n4 \"my dir\"/\n5 \"a b\" -> \"c d\"",
+            &options,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            edited,
+            [
+                Entry::new(None, String::from("2024 report.txt")),
+                Entry::new(Some(3), String::from(" padded ")),
+                Entry {
+                    id: Some(4),
+                    ..Entry::new_dir(String::from("my dir"))
+                },
+                Entry::new_symlink(Some(5), String::from("a b"), String::from("c d")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_text_after_quoted_name() {
+        let options = Options::default();
+        for line in [
+            "\"a\" junk",
+            "0 \"a\"/ junk",
+            "1 \"a\" -> \"b\" junk",
+            "\"a\"x",
+        ] {
+            assert!(
+                matches!(
+                    str_to_entries(line, &options, false),
+                    Err(TreeEditError::InvalidEntry(_))
+                ),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_tree_format_round_trip() {
+        let entries = vec![
+            Entry::new(Some(0), String::from("./b.txt")),
+            Entry::new_dir(String::from("./src")),
+            Entry::new(Some(1), String::from("./src/main.rs")),
+            Entry::new_dir(String::from("./src/fsutils")),
+            Entry::new(Some(2), String::from("./src/fsutils/fsop.rs")),
+            Entry::new(Some(3), String::from("./src/lib.rs")),
+            Entry::new_symlink(
+                Some(4),
+                String::from("./src/link"),
+                String::from("../b.txt"),
+            ),
+        ];
+        let options = Options {
+            format: BufferFormat::Tree,
+            ..Default::default()
+        };
+        let s = entries_to_str(&entries, &options);
+        assert_eq!(
+            without_header(&s),
+            [
+                "0 ./b.txt",
+                "  ./src/",
+                "    fsutils/",
+                "2     fsop.rs",
+                "3   lib.rs",
+                "4   link -> ../b.txt",
+                "1   main.rs",
+            ]
+            .join("\n")
+        );
+        let mut parsed = str_to_entries(&s, &options, false).unwrap();
+        parsed.sort_by(|a, b| a.path.cmp(&b.path));
+        let mut entries = entries;
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(parsed, entries);
+    }
+
+    #[test]
+    fn test_tree_format_moving_line_changes_parent() {
+        // y was moved from b/ to a/, new/ is created in b/
+        let s = [
+            "  a/", "1   x", "2   y", "  b/", "3   z", "    new/", "      w",
+        ]
+        .join("\n");
+        let options = Options {
+            format: BufferFormat::Tree,
+            ..Default::default()
+        };
+        let paths = str_to_entries(&s, &options, false)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.path.to_string_lossy().into_owned())
+            .collect::<Vec<String>>();
+        assert_eq!(paths, ["a", "a/x", "a/y", "b", "b/z", "b/new", "b/new/w"]);
+    }
+
+    #[test]
+    fn test_mode_column() {
+        let options = Options {
+            mode_column: true,
+            ..Default::default()
+        };
+        let entries = vec![
+            Entry {
+                mode: Some(0o755),
+                ..Entry::new_dir(String::from("bin"))
+            },
+            Entry {
+                mode: Some(0o4755),
+                ..Entry::new(Some(0), String::from("bin/run"))
+            },
+            Entry::new_symlink(Some(1), String::from("link"), String::from("bin/run")),
+        ];
+        let s = entries_to_str(&entries, &options);
+        assert_eq!(
+            without_header(&s),
+            [
+                "  rwxr-xr-x bin/",
+                "0 rwsr-xr-x bin/run",
+                "1           link -> bin/run"
+            ]
+            .join("\n")
+        );
+        assert_eq!(str_to_entries(&s, &options, false).unwrap(), entries);
+        let edited = str_to_entries(
+            "0 0640 bin/run\n  rwxrwxrwT tmp/\n0644 new",
+            &options,
+            false,
+        )
+        .unwrap();
+        assert_eq!(edited[0].mode, Some(0o640));
+        assert_eq!(edited[1].mode, Some(0o1776));
+        assert_eq!((edited[2].id, edited[2].mode), (None, Some(0o644)));
+    }
+
+    #[test]
+    fn test_owner_column() {
+        let options = Options {
+            mode_column: true,
+            owner_column: true,
+            ..Default::default()
+        };
+        let owner = |user: &str, group: &str| {
+            Some(Owner {
+                user: user.to_string(),
+                group: group.to_string(),
+            })
+        };
+        let entries = vec![
+            Entry {
+                mode: Some(0o750),
+                owner: owner("root", "staff"),
+                ..Entry::new_dir(String::from("data"))
+            },
+            Entry {
+                mode: Some(0o640),
+                owner: owner("alice", "alice"),
+                ..Entry::new(Some(0), String::from("data/report"))
+            },
+        ];
+        let s = entries_to_str(&entries, &options);
+        assert_eq!(
+            without_header(&s),
+            [
+                "  rwxr-x--- root:staff  data/",
+                "0 rw-r----- alice:alice data/report",
+            ]
+            .join("\n")
+        );
+        assert_eq!(str_to_entries(&s, &options, false).unwrap(), entries);
+        // a name alone is never taken for an owner
+        let edited = str_to_entries("0 bob:staff data/report\n1 a:b", &options, false).unwrap();
+        assert_eq!(edited[0].owner, owner("bob", "staff"));
+        assert_eq!(
+            (edited[1].owner.clone(), edited[1].path.to_str().unwrap()),
+            (None, "a:b")
+        );
+    }
+
+    #[test]
+    fn test_git_status_markers() {
+        let options = Options::default();
+        let info = |git_status: Option<&str>| {
+            Some(Info {
+                size: 0,
+                mtime: 0,
+                file_type: "file",
+                git_status: git_status.map(String::from),
+            })
+        };
+        let entries = vec![
+            Entry {
+                info: info(Some(" M")),
+                ..Entry::new(Some(0), String::from("changed"))
+            },
+            Entry {
+                info: info(None),
+                ..Entry::new(Some(1), String::from("clean"))
+            },
+            Entry {
+                info: info(Some("??")),
+                ..Entry::new(Some(2), String::from("new"))
+            },
+        ];
+        let s = entries_to_str(&entries, &options);
+        assert!(s.contains("# Git status comes first"), "{}", s);
+        assert_eq!(
+            without_header(&s),
+            ["0 changed  #  M", "1 clean    #", "2 new      # ??",].join("\n")
+        );
+        let without_info = entries
+            .iter()
+            .map(|e| Entry {
+                info: None,
+                ..e.clone()
+            })
+            .collect::<Vec<Entry>>();
+        assert!(is_annotated(&entries, &options));
+        assert_eq!(str_to_entries(&s, &options, true).unwrap(), without_info);
+    }
+
+    #[test]
+    fn test_annotation_columns() {
+        let options = Options {
+            columns: vec![Column::Size, Column::Type],
+            ..Default::default()
+        };
+        let info = |size, file_type| {
+            Some(Info {
+                size,
+                mtime: 0,
+                file_type,
+                git_status: None,
+            })
+        };
+        let entries = vec![
+            Entry {
+                info: info(4096, "dir"),
+                ..Entry::new_dir(String::from("src"))
+            },
+            Entry {
+                info: info(1536, "file"),
+                ..Entry::new(Some(0), String::from("src/main.rs"))
+            },
+            Entry {
+                info: info(7, "symlink"),
+                ..Entry::new_symlink(Some(1), String::from("main"), String::from("src/main.rs"))
+            },
+        ];
+        let s = entries_to_str(&entries, &options);
+        assert_eq!(
+            without_header(&s),
+            [
+                "  src/                 #    -  dir",
+                "0 src/main.rs          # 1.5K  file",
+                "1 main -> src/main.rs  #    7  symlink",
+            ]
+            .join("\n")
+        );
+        let without_info = entries
+            .iter()
+            .map(|e| Entry {
+                info: None,
+                ..e.clone()
+            })
+            .collect::<Vec<Entry>>();
+        assert_eq!(str_to_entries(&s, &options, true).unwrap(), without_info);
+        // edited or broken annotations never end up in the path
+        let edited = str_to_entries(
+            "0 src/lib.rs  # 12K\n1 main -> lib.rs  #\n  # file",
+            &options,
+            true,
+        )
+        .unwrap();
+        assert_eq!(edited.len(), 2);
+        assert_eq!(edited[0].path, Path::new("src/lib.rs"));
+        assert_eq!(
+            edited[1].kind,
+            EntryKind::Symlink {
+                target: PathBuf::from("lib.rs")
+            }
+        );
+        // a typed quoted name holding the marker, with or without an annotation
+        let typed = str_to_entries(
+            "\"new  #1\"\n0 \"a \\\"  #\"  # 12K\n1 x -> \"t  #\"  #    7  symlink",
+            &options,
+            true,
+        )
+        .unwrap();
+        assert_eq!(typed[0], Entry::new(None, String::from("new  #1")));
+        assert_eq!(typed[1], Entry::new(Some(0), String::from("a \"  #")));
+        assert_eq!(
+            typed[2],
+            Entry::new_symlink(Some(1), String::from("x"), String::from("t  #"))
+        );
+    }
+
+    #[test]
+    fn test_read_edit_script() -> crate::Result<()> {
+        let options = Options::default();
+        let entries = vec![Entry::new(Some(0), String::from("./a"))];
+        let script = "# a comment\n0 ./b\n./c/\n";
+        let expected = vec![
+            Entry::new(Some(0), String::from("./b")),
+            Entry::new_dir(String::from("./c")),
+        ];
+        let tmp = TmpDir::new("tree-edit-script")?;
+        let path = tmp.path().join("script");
+        fs::write(&path, script)?;
+        // stdin is only read for -
+        let unread = io::repeat(b'x').take(1);
+        assert_eq!(
+            read_edit_script_from(&path, unread, &entries, &options)?,
+            expected
+        );
+        assert_eq!(
+            read_edit_script_from(Path::new("-"), script.as_bytes(), &entries, &options)?,
+            expected
+        );
+        assert!(
+            read_edit_script_from(&tmp.path().join("missing"), &b""[..], &entries, &options)
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_listing() -> io::Result<()> {
+        let entries = vec![
+            Entry::new_dir(String::from("./src")),
+            Entry::new(Some(0), String::from("./src/main.rs")),
+            Entry::new(Some(1), String::from("./README.md")),
+        ];
+        for (format, body) in [
+            (
+                BufferFormat::Flat,
+                "  ./src/\n0 ./src/main.rs\n1 ./README.md\n",
+            ),
+            (BufferFormat::Tree, "1 ./README.md\n  ./src/\n0   main.rs\n"),
+        ] {
+            let options = Options {
+                format,
+                root: PathBuf::from("/data"),
+                ..Default::default()
+            };
+            let mut out = Vec::new();
+            write_listing(&mut out, &entries, &options)?;
+            let out = String::from_utf8(out).unwrap();
+            assert!(out.starts_with("# tree-edit /data: 2 files, 1 directories\n"));
+            assert!(out.ends_with(body), "{}", out);
+            // what is printed reads back as the same listing
+            let mut parsed = str_to_entries(&out, &options, false).unwrap();
+            parsed.sort_by(|a, b| a.path.cmp(&b.path));
+            let mut sorted = entries.clone();
+            sorted.sort_by(|a, b| a.path.cmp(&b.path));
+            assert_eq!(parsed, sorted);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_confirmation() -> io::Result<()> {
+        for (input, confirmed) in [
+            ("y\n", true),
+            ("YES\n", true),
+            (" yes \n", true),
+            ("n\n", false),
+            ("\n", false),
+            ("", false),
+        ] {
+            assert_eq!(
+                read_confirmation(&mut input.as_bytes())?,
+                confirmed,
+                "{:?}",
+                input
+            );
+        }
+        Ok(())
+    }
+}